@@ -0,0 +1,37 @@
+use std::fmt;
+
+/// Errors returned by the `Client` methods.
+#[derive(Debug)]
+pub enum Error {
+    /// The request could not be sent or the response could not be read, after retrying failed connections.
+    Transport(reqwest::Error),
+    /// The server did not answer within the configured timeout.
+    Timeout,
+    /// The server rejected the request. Holds the status code and the body (usually the error message).
+    Status { status: u16, body: String },
+    /// The server answered successfully but the body was not in the expected shape.
+    Malformed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "failed to reach server: {}", e),
+            Error::Timeout => write!(f, "request timed out"),
+            Error::Status { status, body } => write!(f, "server returned {}: {}", status, body),
+            Error::Malformed(message) => write!(f, "malformed response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Error::Timeout
+        } else {
+            Error::Transport(e)
+        }
+    }
+}
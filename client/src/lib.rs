@@ -0,0 +1,544 @@
+//! Async client for the encryption server.
+//!
+//! Wraps every endpoint in a typed method. Requests that fail to connect to the server are retried with a fixed
+//! delay. Nothing else is retried, as the server may already have acted on a request that timed out or got a
+//! 5xx response, and not every request is safe to repeat (`/tokenize`, envelope verification).
+
+use field_crypto::format::Format;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod error;
+
+pub use error::Error;
+
+/// How requests prove the caller's identity to a server with authentication enabled.
+#[derive(Clone)]
+enum Credentials {
+    None,
+    ApiKey(String),
+    Hmac { client_id: String, secret: String },
+}
+
+/// Builder for a `Client`, to change the timeout, retry policy and credentials.
+pub struct ClientBuilder {
+    base_url: String,
+    credentials: Credentials,
+    root_certificates: Vec<Vec<u8>>,
+    identity: Option<(Vec<u8>, String)>,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl ClientBuilder {
+    /// Authenticate with a static API key.
+    pub fn api_key(mut self, api_key: &str) -> Self {
+        self.credentials = Credentials::ApiKey(api_key.to_string());
+        self
+    }
+
+    /// Authenticate by signing every request with a shared secret.
+    pub fn hmac(mut self, client_id: &str, secret: &str) -> Self {
+        self.credentials = Credentials::Hmac {
+            client_id: client_id.to_string(),
+            secret: secret.to_string(),
+        };
+        self
+    }
+
+    /// Trust an additional CA for an `https` server, given as PEM.
+    pub fn root_certificate(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Present a client certificate to a server verifying them, given as a PKCS#12 archive and its password.
+    pub fn identity(mut self, pkcs12: &[u8], password: &str) -> Self {
+        self.identity = Some((pkcs12.to_vec(), password.to_string()));
+        self
+    }
+
+    /// Maximum time for a single attempt, from sending the request to reading the whole body.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of additional attempts after the first one fails to connect.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Time to wait between attempts.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let mut http = reqwest::Client::builder().timeout(self.timeout);
+        for pem in &self.root_certificates {
+            http = http.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        if let Some((pkcs12, password)) = &self.identity {
+            http = http.identity(reqwest::Identity::from_pkcs12_der(pkcs12, password)?);
+        }
+        Ok(Client {
+            http: http.build()?,
+            base_url: self.base_url.trim_end_matches('/').to_string(),
+            credentials: self.credentials,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+        })
+    }
+}
+
+/// A data key from `Client::data_key`.
+pub struct DataKey {
+    /// Id of the server key that wrapped it.
+    pub key_id: String,
+    pub plaintext: Vec<u8>,
+    /// Base64 wrapped key, for `Client::unwrap`.
+    pub wrapped: String,
+}
+
+/// The path of a `/keys/{id}/...` endpoint, with the key id percent-encoded.
+fn key_path(key_id: &str, operation: &str) -> String {
+    format!("/keys/{}/{}", percent_encode(key_id), operation)
+}
+
+/// Percent-encode a query string value or a path segment: every byte but the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn status_error(status: u16, body: &[u8]) -> Error {
+    Error::Status {
+        status,
+        body: String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn string_field(response: &Value, name: &str) -> Result<String, Error> {
+    response
+        .get(name)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| Error::Malformed(format!("missing {}", name)))
+}
+
+/// A client for one server, identified by its base url (e.g. `http://localhost:8080`).
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    credentials: Credentials,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl Client {
+    /// A client with a 10 second timeout and 3 connection retries one second apart.
+    pub fn new(base_url: &str) -> Result<Client, Error> {
+        Client::builder(base_url).build()
+    }
+
+    pub fn builder(base_url: &str) -> ClientBuilder {
+        ClientBuilder {
+            base_url: base_url.to_string(),
+            credentials: Credentials::None,
+            root_certificates: vec![],
+            identity: None,
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Send `body` to `path` with extra `headers` once, returning the status code and the response body.
+    async fn attempt(
+        &self,
+        path: &str,
+        body: &[u8],
+        headers: &[(&str, &str)],
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let mut request = self
+            .http
+            .post(format!("{}{}", self.base_url, path))
+            .body(body.to_vec());
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = match &self.credentials {
+            Credentials::None => request,
+            Credentials::ApiKey(api_key) => request.header("X-Api-Key", api_key),
+            Credentials::Hmac { client_id, secret } => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|now| now.as_secs())
+                    .unwrap_or(0);
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                    .expect("hmac accepts keys of any size");
+                let body_sha256 = field_crypto::sha256_hex(body);
+                mac.update(
+                    field_crypto::request_signing_input_with_hash(
+                        "POST",
+                        path,
+                        timestamp,
+                        &body_sha256,
+                    )
+                    .as_bytes(),
+                );
+                request
+                    // lets the server check bodies larger than its limit as they stream in
+                    .header("X-Content-Sha256", body_sha256)
+                    .header("X-Client-Id", client_id)
+                    .header("X-Timestamp", timestamp.to_string())
+                    .header("X-Signature", base64::encode(mac.finalize().into_bytes()))
+            }
+        };
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        Ok((status, response.bytes().await?.to_vec()))
+    }
+
+    /// Send `body` to `path`, retrying while it fails to connect.
+    /// Returns the status code and the body of any response below 500.
+    pub async fn post_raw(&self, path: &str, body: &str) -> Result<(u16, String), Error> {
+        let (status, body) = self.post_bytes(path, body.as_bytes(), &[]).await?;
+        Ok((status, String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    /// `post_raw` for any content, with extra headers.
+    async fn post_bytes(
+        &self,
+        path: &str,
+        body: &[u8],
+        headers: &[(&str, &str)],
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(path, body, headers).await {
+                // the request was never sent, so sending it again cannot repeat it
+                Err(Error::Transport(e)) if e.is_connect() && attempt < self.retries => {}
+                Ok((status, body)) if status >= 500 => return Err(status_error(status, &body)),
+                result => return result,
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry_delay).await;
+        }
+    }
+
+    /// `post_bytes` of an `application/octet-stream` body.
+    async fn post_octets(
+        &self,
+        path: &str,
+        body: &[u8],
+        headers: &[(&str, &str)],
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let mut headers = headers.to_vec();
+        headers.push(("Content-Type", "application/octet-stream"));
+        self.post_bytes(path, body, &headers).await
+    }
+
+    /// Send `body` to `path` and fail with `Error::Status` unless the response is a success.
+    pub async fn post_text(&self, path: &str, body: &str) -> Result<(u16, String), Error> {
+        match self.post_raw(path, body).await? {
+            (status, body) if (200..300).contains(&status) => Ok((status, body)),
+            (status, body) => Err(Error::Status { status, body }),
+        }
+    }
+
+    async fn post_json(&self, path: &str, body: &Value) -> Result<Value, Error> {
+        let (_, body) = self.post_text(path, &body.to_string()).await?;
+        serde_json::from_str(&body).map_err(|e| Error::Malformed(e.to_string()))
+    }
+
+    /// Send a document encoded in `format` to `path`, answered in the same format.
+    async fn post_encoded(
+        &self,
+        path: &str,
+        format: Format,
+        body: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let headers = [
+            ("Content-Type", format.media_type()),
+            ("Accept", format.media_type()),
+        ];
+        match self.post_bytes(path, body, &headers).await? {
+            (200, body) => Ok(body),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    }
+
+    /// `encrypt` of a document encoded in `format`, see `field_crypto::format`. Ciphertexts come back as byte
+    /// strings where the format has them.
+    pub async fn encrypt_as(&self, format: Format, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.post_encoded("/encrypt", format, data).await
+    }
+
+    /// `decrypt` of a document encoded in `format`.
+    pub async fn decrypt_as(&self, format: Format, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.post_encoded("/decrypt", format, data).await
+    }
+
+    /// Encrypt the fields selected by the server's current configuration.
+    pub async fn encrypt(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/encrypt", data).await
+    }
+
+    /// Encrypt the fields the schema uploaded as `schema` marks, after the server validates `data` against it.
+    pub async fn encrypt_with_schema(&self, schema: &str, data: &Value) -> Result<Value, Error> {
+        self.post_json(&format!("/encrypt?schema={}", percent_encode(schema)), data)
+            .await
+    }
+
+    /// The personal data `encrypt` would leave in clear, `{"<JSON Pointer>": "<kind>"}`.
+    pub async fn detect_pii(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/pii", data)
+            .await?
+            .get("detected")
+            .cloned()
+            .ok_or_else(|| Error::Malformed("missing detected".to_string()))
+    }
+
+    /// The fields `encrypt` would change, `[{"path", "action", "reason", "error"}]`, without encrypting them.
+    pub async fn plan_encryption(&self, data: &Value) -> Result<Value, Error> {
+        self.planned_fields("/encrypt?dryRun=true", data).await
+    }
+
+    /// The fields `decrypt` would decrypt, like `plan_encryption`.
+    pub async fn plan_decryption(&self, data: &Value) -> Result<Value, Error> {
+        self.planned_fields("/decrypt?dryRun=true", data).await
+    }
+
+    async fn planned_fields(&self, path: &str, data: &Value) -> Result<Value, Error> {
+        self.post_json(path, data)
+            .await?
+            .get("fields")
+            .cloned()
+            .ok_or_else(|| Error::Malformed("missing fields".to_string()))
+    }
+
+    /// Decrypt every encrypted string in `data`.
+    pub async fn decrypt(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/decrypt", data).await
+    }
+
+    /// Replace the fields the server tokenizes with tokens from its vault.
+    pub async fn tokenize(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/tokenize", data).await
+    }
+
+    /// Replace the tokens of the fields the server tokenizes with their values from its vault.
+    pub async fn detokenize(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/detokenize", data).await
+    }
+
+    /// Get the base64 signature of the plaintext `data`.
+    pub async fn sign(&self, data: &Value) -> Result<String, Error> {
+        self.post_json("/sign", data)
+            .await?
+            .get("signature")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| Error::Malformed("missing signature".to_string()))
+    }
+
+    /// Check `signature` against `data`, which may contain encrypted fields.
+    /// Returns `Ok(false)` when the signature does not match, and an error when the request itself is invalid.
+    pub async fn verify(&self, data: &Value, signature: &str) -> Result<bool, Error> {
+        let body = json!({ "data": data, "signature": signature }).to_string();
+        match self.post_raw("/verify", &body).await? {
+            (204, _) => Ok(true),
+            // a mismatched signature is a bare 400, invalid requests carry a message
+            (400, body) if body.is_empty() => Ok(false),
+            (status, body) => Err(Error::Status { status, body }),
+        }
+    }
+
+    /// Get the base64 signature of arbitrary content, in the same format as `sign`.
+    pub async fn sign_raw(&self, content: &[u8]) -> Result<String, Error> {
+        match self.post_octets("/sign/raw", content, &[]).await? {
+            (200, body) => string_field(
+                &serde_json::from_slice(&body).map_err(|e| Error::Malformed(e.to_string()))?,
+                "signature",
+            ),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    }
+
+    /// Check a `sign_raw` signature against `content`. Like `verify`, returns `Ok(false)` when it does not match.
+    pub async fn verify_raw(&self, content: &[u8], signature: &str) -> Result<bool, Error> {
+        let headers = [("X-Content-Signature", signature)];
+        match self.post_octets("/verify/raw", content, &headers).await? {
+            (204, _) => Ok(true),
+            (400, body) if body.is_empty() => Ok(false),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    }
+
+    /// Encrypt any content into a blob for the server's key, see `field_crypto::blob`. `BlobEncryptor` does the
+    /// same locally with only the public key.
+    pub async fn encrypt_blob(&self, content: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.post_octets("/encrypt/blob", content, &[]).await? {
+            (200, body) => Ok(body),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    }
+
+    /// Decrypt a blob made by `encrypt_blob`.
+    pub async fn decrypt_blob(&self, blob: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.post_octets("/decrypt/blob", blob, &[]).await? {
+            (200, body) => Ok(body),
+            (status, body) => Err(status_error(status, &body)),
+        }
+    }
+
+    /// Sign the plaintext `data` in an envelope valid for `ttl` seconds (the server's default when `None`), that
+    /// only `audience` may verify when given. Returns `{"signature", "envelope"}`, for `verify_envelope`.
+    pub async fn sign_envelope(
+        &self,
+        data: &Value,
+        ttl: Option<u64>,
+        audience: Option<&str>,
+    ) -> Result<Value, Error> {
+        let mut path = "/sign?envelope=true".to_string();
+        if let Some(ttl) = ttl {
+            path.push_str(&format!("&ttl={}", ttl));
+        }
+        if let Some(audience) = audience {
+            path.push_str(&format!("&audience={}", percent_encode(audience)));
+        }
+        self.post_json(&path, data).await
+    }
+
+    /// Check a `sign_envelope` result against `data`, as `audience`. Like `verify`, returns `Ok(false)` when the
+    /// signature does not match; an expired, reused or misdirected envelope is an `Error::Status` naming why.
+    pub async fn verify_envelope(
+        &self,
+        data: &Value,
+        signed: &Value,
+        audience: Option<&str>,
+    ) -> Result<bool, Error> {
+        let path = match audience {
+            Some(audience) => format!("/verify?audience={}", percent_encode(audience)),
+            None => "/verify".to_string(),
+        };
+        let body = json!({
+            "data": data,
+            "signature": signed.get("signature"),
+            "envelope": signed.get("envelope"),
+        });
+        match self.post_raw(&path, &body.to_string()).await? {
+            (204, _) => Ok(true),
+            (400, body) if body.is_empty() => Ok(false),
+            (status, body) => Err(Error::Status { status, body }),
+        }
+    }
+
+    /// Sign every field of the plaintext `data` on its own. Returns the signatures by JSON Pointer.
+    pub async fn sign_fields(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/sign?perField=true", data)
+            .await?
+            .get("signatures")
+            .cloned()
+            .ok_or_else(|| Error::Malformed("missing signatures".to_string()))
+    }
+
+    /// Check the field `signatures` of `sign_fields` against `data`, which may contain encrypted fields.
+    /// Returns the JSON Pointers of the fields that are `verified`, `failed` or `unsigned`.
+    pub async fn verify_fields(&self, data: &Value, signatures: &Value) -> Result<Value, Error> {
+        self.post_json(
+            "/verify",
+            &json!({ "data": data, "signatures": signatures }),
+        )
+        .await
+    }
+
+    /// Wrap a data key with the server key `key_id` (or `current`). Returns the base64 wrapped key.
+    pub async fn wrap(&self, key_id: &str, data_key: &[u8]) -> Result<String, Error> {
+        let body = json!({ "plaintext": base64::encode(data_key) });
+        string_field(
+            &self.post_json(&key_path(key_id, "wrap"), &body).await?,
+            "ciphertextBlob",
+        )
+    }
+
+    /// Unwrap a data key wrapped by `wrap` or `data_key`.
+    pub async fn unwrap(&self, key_id: &str, wrapped: &str) -> Result<Vec<u8>, Error> {
+        let body = json!({ "ciphertextBlob": wrapped });
+        let plaintext = string_field(
+            &self.post_json(&key_path(key_id, "unwrap"), &body).await?,
+            "plaintext",
+        )?;
+        base64::decode(plaintext).map_err(|e| Error::Malformed(e.to_string()))
+    }
+
+    /// Generate a data key of `bytes` bytes, both in plaintext and wrapped with the server's current key.
+    pub async fn data_key(&self, bytes: usize) -> Result<DataKey, Error> {
+        let response = self
+            .post_json("/datakey", &json!({ "numberOfBytes": bytes }))
+            .await?;
+        Ok(DataKey {
+            key_id: string_field(&response, "keyId")?,
+            plaintext: base64::decode(string_field(&response, "plaintext")?)
+                .map_err(|e| Error::Malformed(e.to_string()))?,
+            wrapped: string_field(&response, "ciphertextBlob")?,
+        })
+    }
+
+    /// Move the fields encrypted with a retired server key to the current one. Returns the report, with the
+    /// migrated document in `data`.
+    pub async fn reencrypt(&self, data: &Value) -> Result<Value, Error> {
+        self.post_json("/reencrypt", data).await
+    }
+
+    /// Replace the list of fields the server encrypts.
+    pub async fn set_config(&self, fields_to_encrypt: &[String]) -> Result<(), Error> {
+        self.post_text(
+            "/config",
+            &json!({ "fieldsToEncrypt": fields_to_encrypt }).to_string(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// `set_config` with what to do with other fields instead, such as
+    /// `{"card": {"action": "mask", "pattern": "****####"}}`.
+    pub async fn set_field_actions(
+        &self,
+        fields_to_encrypt: &[String],
+        field_actions: &Value,
+    ) -> Result<(), Error> {
+        self.post_text(
+            "/config",
+            &json!({ "fieldsToEncrypt": fields_to_encrypt, "fieldActions": field_actions })
+                .to_string(),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Add or replace the JSON Schema named `name`, for `encrypt_with_schema`.
+    pub async fn upload_schema(&self, name: &str, schema: &Value) -> Result<(), Error> {
+        self.post_text(
+            &format!("/schemas/{}", percent_encode(name)),
+            &schema.to_string(),
+        )
+        .await
+        .map(|_| ())
+    }
+}
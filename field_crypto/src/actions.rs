@@ -0,0 +1,119 @@
+//! What to do with a field besides encrypting it, for documents bound for logs or analytics that must not
+//! hold the value at all.
+//!
+//! The masked, hashed and tokenized forms are computed from the text of the value: a string itself, any other
+//! value its JSON serialization.
+
+use crate::{encrypt_field, pointer_push, sha256_hex};
+use rsa::RsaPublicKey;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use zeroize::Zeroizing;
+
+/// What `detect_and_apply` does with a field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldAction {
+    /// Replace the value with its ciphertext, like `detect_and_encrypt`.
+    Encrypt,
+    /// Remove the field.
+    Redact,
+    /// Replace the value with `mask(text, pattern)`.
+    Mask { pattern: String },
+    /// Replace the value with `hash(text, salt)`.
+    Hash { salt: String },
+    /// Replace the value with a token for it.
+    Tokenize,
+}
+
+impl FieldAction {
+    /// The name of the action in `/config`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldAction::Encrypt => "encrypt",
+            FieldAction::Redact => "redact",
+            FieldAction::Mask { .. } => "mask",
+            FieldAction::Hash { .. } => "hash",
+            FieldAction::Tokenize => "tokenize",
+        }
+    }
+}
+
+/// Mask a value with a pattern aligned with its end: each `#` shows the character at that position, if any,
+/// and any other character of the pattern is written as it is. The value before the pattern is left out, so
+/// `****####` masks a card number as `****1234`.
+pub fn mask(text: &str, pattern: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let start = chars.len() as isize - pattern.len() as isize;
+    pattern
+        .iter()
+        .enumerate()
+        .filter_map(|(index, &mask)| match mask {
+            '#' => usize::try_from(start + index as isize)
+                .ok()
+                .map(|index| chars[index]),
+            mask => Some(mask),
+        })
+        .collect()
+}
+
+/// Hex SHA256 of the salt followed by the value.
+pub fn hash(text: &str, salt: &str) -> String {
+    sha256_hex(Zeroizing::new(format!("{}{}", salt, text)).as_bytes())
+}
+
+/// The text of a value: a string itself, any other value its JSON.
+pub fn text(value: &Value) -> Zeroizing<String> {
+    Zeroizing::new(match value {
+        Value::String(string) => string.clone(),
+        value => value.to_string(),
+    })
+}
+
+/// Apply the action named for each key of `actions` to its values, anywhere in `payload`. Fields without an
+/// action are searched for more fields. `tokenize` makes the token of a value.
+pub fn detect_and_apply(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    actions: &BTreeMap<String, FieldAction>,
+    tokenize: &mut dyn FnMut(&Value) -> Result<String, String>,
+) -> Result<Value, String> {
+    apply_at(payload, "", public_key, actions, tokenize)
+}
+
+/// `detect_and_apply` for `payload` at the JSON Pointer `path`.
+fn apply_at(
+    payload: &Value,
+    path: &str,
+    public_key: &RsaPublicKey,
+    actions: &BTreeMap<String, FieldAction>,
+    tokenize: &mut dyn FnMut(&Value) -> Result<String, String>,
+) -> Result<Value, String> {
+    let mut data = payload.clone();
+    match &mut data {
+        Value::Array(vec) => {
+            for (index, entry) in vec.iter_mut().enumerate() {
+                let path = pointer_push(path, &index.to_string());
+                *entry = apply_at(entry, &path, public_key, actions, tokenize)?;
+            }
+        }
+        Value::Object(map) => {
+            map.retain(|key, _| actions.get(key) != Some(&FieldAction::Redact));
+            for (key, value) in map.iter_mut() {
+                let path = pointer_push(path, key);
+                *value = match actions.get(key) {
+                    Some(FieldAction::Encrypt) => json!(encrypt_field(public_key, &path, value)?),
+                    Some(FieldAction::Mask { pattern }) => json!(mask(&text(value), pattern)),
+                    Some(FieldAction::Hash { salt }) => json!(hash(&text(value), salt)),
+                    Some(FieldAction::Tokenize) => json!(tokenize(value)?),
+                    // the value is not to be changed, if the value is an array or object, recurse
+                    Some(FieldAction::Redact) | None => {
+                        apply_at(value, &path, public_key, actions, tokenize)?
+                    }
+                };
+            }
+        }
+        _ => {}
+    }
+    Ok(data)
+}
@@ -0,0 +1,283 @@
+//! Encryption of arbitrary content too large for one RSA block, such as attachments.
+//!
+//! A blob is a header followed by AES-256-GCM chunks of a random data key, which the header holds wrapped with
+//! the server's public key:
+//!
+//! | bytes | content                                             |
+//! |-------|-----------------------------------------------------|
+//! | 4     | `FCB1`                                              |
+//! | 4     | plaintext chunk size, big endian                    |
+//! | 7     | random nonce prefix                                 |
+//! | 2     | length of the wrapped key, big endian               |
+//! | n     | data key encrypted like a field (RSA PKCS#1 v1.5)   |
+//!
+//! Every chunk but the last holds exactly the chunk size of plaintext. The nonce of a chunk is the prefix, its
+//! index (4 bytes, big endian) and 1 for the last chunk or 0 otherwise, and the whole header is its associated
+//! data, so chunks cannot be reordered, dropped, truncated or moved to another blob.
+//!
+//! Both ends work on a stream: `update` takes the next input bytes and returns the output ready so far, and
+//! `finish` returns the rest once the input ended.
+
+use crate::wrap_data_key;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rsa::RsaPublicKey;
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 4] = b"FCB1";
+/// Plaintext bytes per chunk written.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Largest chunk size accepted when reading, to bound the memory a blob can make the reader use.
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+/// Size of the header before the wrapped key.
+const FIXED_HEADER_SIZE: usize = 4 + 4 + NONCE_PREFIX_SIZE + 2;
+/// Largest wrapped key accepted, an 8192 bit RSA block.
+const MAX_WRAPPED_KEY_SIZE: usize = 1024;
+/// Every failure to decrypt a blob looks the same.
+const BLOB_DECRYPTION_FAILED: &str = "blob decryption failed";
+
+/// Either end of the blob format.
+pub trait BlobStream {
+    /// Take the next bytes of input, returning the output ready so far.
+    fn update(&mut self, input: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// End the input, returning the rest of the output.
+    fn finish(&mut self) -> Result<Vec<u8>, String>;
+}
+
+/// The key, nonce prefix and associated data of a blob, and how many chunks went through.
+struct Chunks {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    header: Vec<u8>,
+    chunk_size: usize,
+    index: u32,
+}
+
+impl Chunks {
+    fn nonce(&self, last: bool) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&self.index.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    fn next_index(&mut self) -> Result<(), String> {
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or("blob has too many chunks")?;
+        Ok(())
+    }
+
+    fn seal(&mut self, plaintext: &[u8], last: bool) -> Result<Vec<u8>, String> {
+        let nonce = self.nonce(last);
+        let sealed = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|_| "failed to encrypt")?;
+        self.next_index()?;
+        Ok(sealed)
+    }
+
+    fn open(&mut self, ciphertext: &[u8], last: bool) -> Result<Zeroizing<Vec<u8>>, String> {
+        let nonce = self.nonce(last);
+        let opened = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.header,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| BLOB_DECRYPTION_FAILED)?;
+        self.next_index()?;
+        Ok(opened)
+    }
+}
+
+/// Encrypts a blob for the holder of a private key.
+pub struct BlobEncryptor {
+    chunks: Chunks,
+    header_written: bool,
+    /// Plaintext not yet in a chunk, always kept until more follows so the last chunk is known.
+    pending: Zeroizing<Vec<u8>>,
+    finished: bool,
+}
+
+impl BlobEncryptor {
+    /// A blob under a new random data key wrapped with `public_key`.
+    pub fn new(public_key: &RsaPublicKey) -> Result<Self, String> {
+        let mut data_key = Zeroizing::new([0; 32]);
+        OsRng.fill_bytes(&mut *data_key);
+        let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        let wrapped = wrap_data_key(public_key, &*data_key)?;
+        let mut header = Vec::with_capacity(FIXED_HEADER_SIZE + wrapped.len());
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&(CHUNK_SIZE as u32).to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        header.extend_from_slice(&(wrapped.len() as u16).to_be_bytes());
+        header.extend_from_slice(&wrapped);
+        Ok(BlobEncryptor {
+            chunks: Chunks {
+                cipher: Aes256Gcm::new_from_slice(&*data_key).map_err(|_| "invalid data key")?,
+                nonce_prefix,
+                header,
+                chunk_size: CHUNK_SIZE,
+                index: 0,
+            },
+            header_written: false,
+            pending: Zeroizing::new(vec![]),
+            finished: false,
+        })
+    }
+
+    fn take_header(&mut self) -> Vec<u8> {
+        if self.header_written {
+            return vec![];
+        }
+        self.header_written = true;
+        self.chunks.header.clone()
+    }
+}
+
+impl BlobStream for BlobEncryptor {
+    fn update(&mut self, input: &[u8]) -> Result<Vec<u8>, String> {
+        if self.finished {
+            return Err("blob already finished".to_string());
+        }
+        let mut output = self.take_header();
+        self.pending.extend_from_slice(input);
+        let chunk_size = self.chunks.chunk_size;
+        let mut start = 0;
+        while self.pending.len() - start > chunk_size {
+            output.extend(
+                self.chunks
+                    .seal(&self.pending[start..start + chunk_size], false)?,
+            );
+            start += chunk_size;
+        }
+        if start > 0 {
+            let rest = Zeroizing::new(self.pending[start..].to_vec());
+            self.pending = rest;
+        }
+        Ok(output)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        if self.finished {
+            return Err("blob already finished".to_string());
+        }
+        self.finished = true;
+        let mut output = self.take_header();
+        let last = std::mem::take(&mut *self.pending);
+        output.extend(self.chunks.seal(&Zeroizing::new(last), true)?);
+        Ok(output)
+    }
+}
+
+/// Decrypts a blob, given a way to unwrap its data key.
+pub struct BlobDecryptor<F> {
+    unwrap: F,
+    chunks: Option<Chunks>,
+    /// Input not yet decrypted: the header until it is complete, then at most one chunk more than needed.
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl<F: FnMut(&[u8]) -> Result<Zeroizing<Vec<u8>>, String>> BlobDecryptor<F> {
+    /// `unwrap` decrypts the wrapped data key with the private key matching the one the blob was made for.
+    pub fn new(unwrap: F) -> Self {
+        BlobDecryptor {
+            unwrap,
+            chunks: None,
+            pending: vec![],
+            finished: false,
+        }
+    }
+
+    /// Read the header once enough input arrived.
+    fn read_header(&mut self) -> Result<(), String> {
+        if self.chunks.is_some() || self.pending.len() < FIXED_HEADER_SIZE {
+            return Ok(());
+        }
+        if &self.pending[..4] != MAGIC {
+            return Err("not an encrypted blob".to_string());
+        }
+        let chunk_size = u32::from_be_bytes(self.pending[4..8].try_into().unwrap()) as usize;
+        let wrapped_size =
+            u16::from_be_bytes(self.pending[15..FIXED_HEADER_SIZE].try_into().unwrap()) as usize;
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE || wrapped_size > MAX_WRAPPED_KEY_SIZE {
+            return Err("not an encrypted blob".to_string());
+        }
+        let header_size = FIXED_HEADER_SIZE + wrapped_size;
+        if self.pending.len() < header_size {
+            return Ok(());
+        }
+        let header = self.pending[..header_size].to_vec();
+        let data_key = (self.unwrap)(&header[FIXED_HEADER_SIZE..])
+            .map_err(|_| BLOB_DECRYPTION_FAILED.to_string())?;
+        self.chunks = Some(Chunks {
+            cipher: Aes256Gcm::new_from_slice(&data_key)
+                .map_err(|_| BLOB_DECRYPTION_FAILED.to_string())?,
+            nonce_prefix: header[8..15].try_into().unwrap(),
+            header,
+            chunk_size,
+            index: 0,
+        });
+        self.pending.drain(..header_size);
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&[u8]) -> Result<Zeroizing<Vec<u8>>, String>> BlobStream for BlobDecryptor<F> {
+    fn update(&mut self, input: &[u8]) -> Result<Vec<u8>, String> {
+        if self.finished {
+            return Err("blob already finished".to_string());
+        }
+        self.pending.extend_from_slice(input);
+        self.read_header()?;
+        let chunks = match &mut self.chunks {
+            Some(chunks) => chunks,
+            None => return Ok(vec![]),
+        };
+        let sealed_size = chunks.chunk_size + TAG_SIZE;
+        let mut output = vec![];
+        let mut start = 0;
+        // a full chunk is only known not to be the last once more input follows it
+        while self.pending.len() - start > sealed_size {
+            output
+                .extend_from_slice(&chunks.open(&self.pending[start..start + sealed_size], false)?);
+            start += sealed_size;
+        }
+        self.pending.drain(..start);
+        Ok(output)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        if self.finished {
+            return Err("blob already finished".to_string());
+        }
+        self.finished = true;
+        let chunks = self
+            .chunks
+            .as_mut()
+            .ok_or_else(|| "truncated blob".to_string())?;
+        let last = chunks.open(&self.pending, true)?;
+        Ok(last.to_vec())
+    }
+}
@@ -0,0 +1,523 @@
+//! CBOR, MessagePack, YAML and TOML versions of the documents, besides JSON.
+//!
+//! Documents are handled as `serde_json::Value` whatever their format, so signatures do not depend on it. Byte
+//! strings are read as the base64 string of their bytes, which is how JSON holds ciphertexts, and strings that
+//! look like a ciphertext (see `looks_encrypted`) are written back as byte strings. Other byte strings are
+//! refused, as they would come back as text. Map keys must be strings; CBOR and YAML tags are read as their
+//! content, and TOML dates as strings.
+//!
+//! YAML and TOML are configuration files people edit, so `encode_onto` writes the changed values back into the
+//! original document, leaving the rest as it was: its key order, and for TOML its comments and layout too.
+
+use crate::{decode_ciphertext, encode_ciphertext, looks_encrypted};
+use rsa::{PublicKeyParts, RsaPublicKey};
+use serde_json::{Map, Number, Value};
+
+/// A document format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MessagePack,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// The format of a media type without its parameters, if it is one of these.
+    pub fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type.trim().to_ascii_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            "application/cbor" => Some(Format::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Some(Format::Yaml)
+            }
+            "application/toml" | "text/toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn media_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Cbor => "application/cbor",
+            Format::MessagePack => "application/msgpack",
+            Format::Yaml => "application/yaml",
+            Format::Toml => "application/toml",
+        }
+    }
+
+    /// Decode a whole document, whose byte strings must have the shape of ciphertexts for `public_key`.
+    pub fn decode(&self, data: &[u8], public_key: &RsaPublicKey) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut rest = data;
+                let value: ciborium::value::Value = ciborium::de::from_reader(&mut rest)
+                    .map_err(|e| format!("invalid CBOR: {}", e))?;
+                if !rest.is_empty() {
+                    return Err("invalid CBOR: trailing data".to_string());
+                }
+                from_cbor(value, public_key)
+            }
+            Format::MessagePack => {
+                let mut rest = data;
+                let value = rmpv::decode::read_value(&mut rest)
+                    .map_err(|e| format!("invalid MessagePack: {}", e))?;
+                if !rest.is_empty() {
+                    return Err("invalid MessagePack: trailing data".to_string());
+                }
+                from_message_pack(value, public_key)
+            }
+            Format::Yaml => from_yaml(
+                &serde_yaml::from_slice(data).map_err(|e| format!("invalid YAML: {}", e))?,
+            ),
+            Format::Toml => from_toml_table(parse_toml(data)?.iter()),
+        }
+    }
+
+    /// Encode a document, with the ciphertexts for `public_key` as byte strings where the format has them.
+    pub fn encode(&self, value: &Value, public_key: &RsaPublicKey) -> Result<Vec<u8>, String> {
+        let mut encoded = vec![];
+        match self {
+            Format::Json => return serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::ser::into_writer(&to_cbor(value, public_key), &mut encoded)
+                .map_err(|e| e.to_string())?,
+            Format::MessagePack => {
+                rmpv::encode::write_value(&mut encoded, &to_message_pack(value, public_key))
+                    .map_err(|e| e.to_string())?
+            }
+            Format::Yaml => {
+                encoded = serde_yaml::to_string(value)
+                    .map_err(|e| e.to_string())?
+                    .into()
+            }
+            Format::Toml => match to_toml_item(value)?.into_table() {
+                Ok(table) => encoded = toml_edit::DocumentMut::from(table).to_string().into(),
+                Err(_) => return Err("a TOML document must be a table".to_string()),
+            },
+        }
+        Ok(encoded)
+    }
+
+    /// `encode` a document decoded from `original` and then changed, keeping what the format can of
+    /// `original` where nothing changed.
+    pub fn encode_onto(
+        &self,
+        original: &[u8],
+        value: &Value,
+        public_key: &RsaPublicKey,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Yaml => {
+                let mut document =
+                    serde_yaml::from_slice(original).map_err(|e| format!("invalid YAML: {}", e))?;
+                merge_yaml(&mut document, value)?;
+                Ok(serde_yaml::to_string(&document)
+                    .map_err(|e| e.to_string())?
+                    .into())
+            }
+            Format::Toml => {
+                let mut document = parse_toml(original)?;
+                match value {
+                    Value::Object(object) => merge_toml_table(document.as_table_mut(), object)?,
+                    _ => return Err("a TOML document must be a table".to_string()),
+                }
+                Ok(document.to_string().into())
+            }
+            _ => self.encode(value, public_key),
+        }
+    }
+}
+
+fn float(float: f64) -> Result<Value, String> {
+    Number::from_f64(float)
+        .map(Value::Number)
+        .ok_or_else(|| "numbers must be finite".to_string())
+}
+
+/// A byte string as the base64 string of a ciphertext, refusing one that cannot be a ciphertext for
+/// `public_key`, which `to_cbor` and `to_message_pack` would write back as text.
+fn ciphertext(bytes: &[u8], public_key: &RsaPublicKey) -> Result<Value, String> {
+    if bytes.len() != public_key.size() {
+        return Err("byte strings must be ciphertexts of the server's key".to_string());
+    }
+    Ok(Value::String(encode_ciphertext(bytes)))
+}
+
+fn from_cbor(value: ciborium::value::Value, public_key: &RsaPublicKey) -> Result<Value, String> {
+    use ciborium::value::Value as Cbor;
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(bool) => Value::Bool(bool),
+        Cbor::Integer(integer) => {
+            let integer = i128::from(integer);
+            if let Ok(unsigned) = u64::try_from(integer) {
+                Value::from(unsigned)
+            } else {
+                Value::from(i64::try_from(integer).map_err(|_| "integer out of range")?)
+            }
+        }
+        Cbor::Float(number) => float(number)?,
+        Cbor::Text(text) => Value::String(text),
+        Cbor::Bytes(bytes) => ciphertext(&bytes, public_key)?,
+        Cbor::Tag(_, value) => from_cbor(*value, public_key)?,
+        Cbor::Array(vec) => Value::Array(
+            vec.into_iter()
+                .map(|entry| from_cbor(entry, public_key))
+                .collect::<Result<_, _>>()?,
+        ),
+        Cbor::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                match key {
+                    Cbor::Text(key) => map.insert(key, from_cbor(value, public_key)?),
+                    _ => return Err("map keys must be strings".to_string()),
+                };
+            }
+            Value::Object(map)
+        }
+        _ => return Err("unsupported CBOR value".to_string()),
+    })
+}
+
+fn to_cbor(value: &Value, public_key: &RsaPublicKey) -> ciborium::value::Value {
+    use ciborium::value::Value as Cbor;
+    match value {
+        Value::Null => Cbor::Null,
+        Value::Bool(bool) => Cbor::Bool(*bool),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(unsigned), _) => Cbor::Integer(unsigned.into()),
+            (None, Some(signed)) => Cbor::Integer(signed.into()),
+            _ => Cbor::Float(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(string) if looks_encrypted(string, public_key) => {
+            Cbor::Bytes(decode_ciphertext(string).unwrap_or_default())
+        }
+        Value::String(string) => Cbor::Text(string.clone()),
+        Value::Array(vec) => {
+            Cbor::Array(vec.iter().map(|entry| to_cbor(entry, public_key)).collect())
+        }
+        Value::Object(map) => Cbor::Map(
+            map.iter()
+                .map(|(key, value)| (Cbor::Text(key.clone()), to_cbor(value, public_key)))
+                .collect(),
+        ),
+    }
+}
+
+fn from_message_pack(value: rmpv::Value, public_key: &RsaPublicKey) -> Result<Value, String> {
+    use rmpv::Value as MessagePack;
+    Ok(match value {
+        MessagePack::Nil => Value::Null,
+        MessagePack::Boolean(bool) => Value::Bool(bool),
+        MessagePack::Integer(integer) => match (integer.as_u64(), integer.as_i64()) {
+            (Some(unsigned), _) => Value::from(unsigned),
+            (None, Some(signed)) => Value::from(signed),
+            _ => return Err("integer out of range".to_string()),
+        },
+        MessagePack::F32(number) => float(number.into())?,
+        MessagePack::F64(number) => float(number)?,
+        MessagePack::String(string) => {
+            Value::String(string.into_str().ok_or("strings must be valid UTF-8")?)
+        }
+        MessagePack::Binary(bytes) => ciphertext(&bytes, public_key)?,
+        MessagePack::Array(vec) => Value::Array(
+            vec.into_iter()
+                .map(|entry| from_message_pack(entry, public_key))
+                .collect::<Result<_, _>>()?,
+        ),
+        MessagePack::Map(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                match key {
+                    MessagePack::String(key) => map.insert(
+                        key.into_str().ok_or("strings must be valid UTF-8")?,
+                        from_message_pack(value, public_key)?,
+                    ),
+                    _ => return Err("map keys must be strings".to_string()),
+                };
+            }
+            Value::Object(map)
+        }
+        MessagePack::Ext(..) => return Err("unsupported MessagePack extension".to_string()),
+    })
+}
+
+fn to_message_pack(value: &Value, public_key: &RsaPublicKey) -> rmpv::Value {
+    use rmpv::Value as MessagePack;
+    match value {
+        Value::Null => MessagePack::Nil,
+        Value::Bool(bool) => MessagePack::Boolean(*bool),
+        Value::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(unsigned), _) => MessagePack::from(unsigned),
+            (None, Some(signed)) => MessagePack::from(signed),
+            _ => MessagePack::F64(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(string) if looks_encrypted(string, public_key) => {
+            MessagePack::Binary(decode_ciphertext(string).unwrap_or_default())
+        }
+        Value::String(string) => MessagePack::from(string.as_str()),
+        Value::Array(vec) => MessagePack::Array(
+            vec.iter()
+                .map(|entry| to_message_pack(entry, public_key))
+                .collect(),
+        ),
+        Value::Object(map) => MessagePack::Map(
+            map.iter()
+                .map(|(key, value)| {
+                    (
+                        MessagePack::from(key.as_str()),
+                        to_message_pack(value, public_key),
+                    )
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn from_yaml(value: &serde_yaml::Value) -> Result<Value, String> {
+    use serde_yaml::Value as Yaml;
+    Ok(match value {
+        Yaml::Null => Value::Null,
+        Yaml::Bool(bool) => Value::Bool(*bool),
+        Yaml::Number(number) => match (number.as_u64(), number.as_i64()) {
+            (Some(unsigned), _) => Value::from(unsigned),
+            (None, Some(signed)) => Value::from(signed),
+            _ => float(number.as_f64().unwrap_or(f64::NAN))?,
+        },
+        Yaml::String(string) => Value::String(string.clone()),
+        Yaml::Sequence(vec) => Value::Array(vec.iter().map(from_yaml).collect::<Result<_, _>>()?),
+        Yaml::Mapping(entries) => {
+            let mut map = Map::new();
+            for (key, value) in entries {
+                let key = key.as_str().ok_or("map keys must be strings")?;
+                map.insert(key.to_string(), from_yaml(value)?);
+            }
+            Value::Object(map)
+        }
+        Yaml::Tagged(tagged) => from_yaml(&tagged.value)?,
+    })
+}
+
+/// Change `document` into `value`, keeping the entries that did not change as they are.
+fn merge_yaml(document: &mut serde_yaml::Value, value: &Value) -> Result<(), String> {
+    use serde_yaml::Value as Yaml;
+    match (document, value) {
+        (Yaml::Mapping(entries), Value::Object(object)) => {
+            entries.retain(|key, _| key.as_str().is_some_and(|key| object.contains_key(key)));
+            for (key, value) in object {
+                match entries.get_mut(key.as_str()) {
+                    Some(entry) => merge_yaml(entry, value)?,
+                    None => {
+                        entries.insert(Yaml::from(key.as_str()), to_yaml(value)?);
+                    }
+                }
+            }
+        }
+        (Yaml::Sequence(entries), Value::Array(vec)) if entries.len() == vec.len() => {
+            for (entry, value) in entries.iter_mut().zip(vec) {
+                merge_yaml(entry, value)?;
+            }
+        }
+        (document, value) => {
+            if from_yaml(document)? != *value {
+                *document = to_yaml(value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_yaml(value: &Value) -> Result<serde_yaml::Value, String> {
+    serde_yaml::to_value(value).map_err(|e| e.to_string())
+}
+
+fn parse_toml(data: &[u8]) -> Result<toml_edit::DocumentMut, String> {
+    std::str::from_utf8(data)
+        .map_err(|_| "invalid TOML: not UTF-8".to_string())?
+        .parse()
+        .map_err(|e| format!("invalid TOML: {}", e))
+}
+
+fn from_toml_table<'a>(
+    entries: impl Iterator<Item = (&'a str, &'a toml_edit::Item)>,
+) -> Result<Value, String> {
+    let mut map = Map::new();
+    for (key, item) in entries {
+        map.insert(key.to_string(), from_toml_item(item)?);
+    }
+    Ok(Value::Object(map))
+}
+
+fn from_toml_item(item: &toml_edit::Item) -> Result<Value, String> {
+    use toml_edit::Item;
+    match item {
+        Item::None => Ok(Value::Null),
+        Item::Value(value) => from_toml_value(value),
+        Item::Table(table) => from_toml_table(table.iter()),
+        Item::ArrayOfTables(tables) => Ok(Value::Array(
+            tables
+                .iter()
+                .map(|table| from_toml_table(table.iter()))
+                .collect::<Result<_, _>>()?,
+        )),
+    }
+}
+
+fn from_toml_value(value: &toml_edit::Value) -> Result<Value, String> {
+    use toml_edit::Value as Toml;
+    Ok(match value {
+        Toml::String(string) => Value::String(string.value().clone()),
+        Toml::Integer(integer) => Value::from(*integer.value()),
+        Toml::Float(number) => float(*number.value())?,
+        Toml::Boolean(bool) => Value::Bool(*bool.value()),
+        Toml::Datetime(datetime) => Value::String(datetime.value().to_string()),
+        Toml::Array(vec) => {
+            Value::Array(vec.iter().map(from_toml_value).collect::<Result<_, _>>()?)
+        }
+        Toml::InlineTable(table) => {
+            let mut map = Map::new();
+            for (key, value) in table.iter() {
+                map.insert(key.to_string(), from_toml_value(value)?);
+            }
+            Value::Object(map)
+        }
+    })
+}
+
+/// A value as a TOML item: objects as tables and arrays of objects as arrays of tables, like people write them.
+fn to_toml_item(value: &Value) -> Result<toml_edit::Item, String> {
+    use toml_edit::Item;
+    Ok(match value {
+        Value::Object(object) => {
+            let mut table = toml_edit::Table::new();
+            for (key, value) in object {
+                table.insert(key, to_toml_item(value)?);
+            }
+            Item::Table(table)
+        }
+        Value::Array(vec) if is_toml_table(value) => {
+            let mut tables = toml_edit::ArrayOfTables::new();
+            for value in vec {
+                if let Item::Table(table) = to_toml_item(value)? {
+                    tables.push(table);
+                }
+            }
+            Item::ArrayOfTables(tables)
+        }
+        value => Item::Value(to_toml_value(value)?),
+    })
+}
+
+/// Whether a value is written as a table or an array of tables outside of inline values.
+fn is_toml_table(value: &Value) -> bool {
+    match value {
+        Value::Object(_) => true,
+        Value::Array(vec) => !vec.is_empty() && vec.iter().all(Value::is_object),
+        _ => false,
+    }
+}
+
+fn to_toml_value(value: &Value) -> Result<toml_edit::Value, String> {
+    use toml_edit::Value as Toml;
+    Ok(match value {
+        Value::Null => return Err("TOML has no null".to_string()),
+        Value::Bool(bool) => Toml::from(*bool),
+        Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(signed), _) => Toml::from(signed),
+            (None, _) if number.is_u64() => return Err("integer out of range".to_string()),
+            (None, float) => Toml::from(float.unwrap_or_default()),
+        },
+        Value::String(string) => Toml::from(string.as_str()),
+        Value::Array(vec) => Toml::Array(vec.iter().map(to_toml_value).collect::<Result<_, _>>()?),
+        Value::Object(object) => {
+            let mut table = toml_edit::InlineTable::new();
+            for (key, value) in object {
+                table.insert(key, to_toml_value(value)?);
+            }
+            Toml::InlineTable(table)
+        }
+    })
+}
+
+fn merge_toml_table(
+    table: &mut toml_edit::Table,
+    object: &Map<String, Value>,
+) -> Result<(), String> {
+    table.retain(|key, _| object.contains_key(key));
+    for (key, value) in object {
+        let merged = match table.get_mut(key) {
+            Some(item) => merge_toml_item(item, value)?,
+            None => false,
+        };
+        if !merged {
+            // written afresh, to be laid out like a new entry of its kind
+            table.remove(key);
+            table.insert(key, to_toml_item(value)?);
+        }
+    }
+    Ok(())
+}
+
+/// Change `item` into `value`, keeping the entries that did not change as they are. Returns false when it
+/// cannot be done in place, because `item` changes between a table and a value.
+fn merge_toml_item(item: &mut toml_edit::Item, value: &Value) -> Result<bool, String> {
+    use toml_edit::Item;
+    match (item, value) {
+        (Item::Table(table), Value::Object(object)) => {
+            merge_toml_table(table, object)?;
+            Ok(true)
+        }
+        (Item::ArrayOfTables(tables), Value::Array(vec))
+            if tables.len() == vec.len() && vec.iter().all(Value::is_object) =>
+        {
+            for (table, value) in tables.iter_mut().zip(vec) {
+                merge_toml_table(table, value.as_object().unwrap())?;
+            }
+            Ok(true)
+        }
+        // values written inline stay inline
+        (Item::Value(toml), value)
+            if !is_toml_table(value) || toml.is_inline_table() || toml.is_array() =>
+        {
+            merge_toml_value(toml, value)?;
+            Ok(true)
+        }
+        (item, value) => Ok(from_toml_item(item)? == *value),
+    }
+}
+
+fn merge_toml_value(toml: &mut toml_edit::Value, value: &Value) -> Result<(), String> {
+    use toml_edit::Value as Toml;
+    match (toml, value) {
+        (Toml::InlineTable(table), Value::Object(object)) => {
+            table.retain(|key, _| object.contains_key(key));
+            for (key, value) in object {
+                match table.get_mut(key) {
+                    Some(toml) => merge_toml_value(toml, value)?,
+                    None => {
+                        table.insert(key, to_toml_value(value)?);
+                    }
+                }
+            }
+        }
+        (Toml::Array(vec), Value::Array(values)) if vec.len() == values.len() => {
+            for (toml, value) in vec.iter_mut().zip(values) {
+                merge_toml_value(toml, value)?;
+            }
+        }
+        (toml, value) => {
+            if from_toml_value(toml)? != *value {
+                // keep the spacing and the comment after the value
+                let decor = toml.decor().clone();
+                *toml = to_toml_value(value)?;
+                *toml.decor_mut() = decor;
+            }
+        }
+    }
+    Ok(())
+}
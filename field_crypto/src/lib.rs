@@ -0,0 +1,233 @@
+//! Encrypt-side half of the server's wire format.
+//!
+//! Everything needed to produce ciphertext and signing input that the server understands, using only the
+//! published public key. Encrypted fields are `BOUND_FIELD_PREFIX` and the JSON array of the JSON Pointer the
+//! field is at and its original value, encrypted with RSA PKCS#1 v1.5 and then base64 encoded. Signatures are RSA PKCS#1 v1.5 over the SHA256 of the canonical
+//! (compact, key sorted) JSON serialization of the plaintext document.
+
+use actions::{detect_and_apply, FieldAction};
+use crypto_hash::{digest, Algorithm};
+use rand::rngs::OsRng;
+use rsa::pkcs8::{FromPublicKey, ToPublicKey};
+use rsa::{PaddingScheme, PublicKey, PublicKeyParts, RsaPublicKey};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub use rsa;
+
+pub mod actions;
+pub mod blob;
+pub mod format;
+pub mod pii;
+
+/// Parse a PEM encoded (SPKI) public key, as written by the server on first start.
+pub fn public_key_from_pem(pem: &str) -> Result<RsaPublicKey, String> {
+    RsaPublicKey::from_public_key_pem(pem).map_err(|_| "invalid public key".to_string())
+}
+
+/// Read a PEM encoded public key from a file.
+pub fn load_public_key<T: AsRef<Path>>(public_file: T) -> Result<RsaPublicKey, String> {
+    public_key_from_pem(&fs::read_to_string(public_file).map_err(|_| "failed to read public key")?)
+}
+
+/// Identifier of a public key: `SHA256:` followed by the unpadded base64 SHA256 of its DER (SPKI) encoding.
+pub fn fingerprint(public_key: &RsaPublicKey) -> String {
+    let der = public_key
+        .to_public_key_der()
+        .expect("failed to serialize public key");
+    format!(
+        "SHA256:{}",
+        base64::encode_config(
+            digest(Algorithm::SHA256, der.as_ref()),
+            base64::STANDARD_NO_PAD
+        )
+    )
+}
+
+/// Encode raw ciphertext bytes into the string form stored in documents.
+pub fn encode_ciphertext(data: &[u8]) -> String {
+    base64::encode(data)
+}
+
+/// Decode the string form of a ciphertext back into raw bytes.
+pub fn decode_ciphertext(data: &str) -> Option<Vec<u8>> {
+    base64::decode(data).ok()
+}
+
+/// Whether a string has the shape of a ciphertext made with `public_key`: valid base64 of exactly one RSA block.
+/// Only the shape is checked, the string may still fail to decrypt.
+pub fn looks_encrypted(data: &str, public_key: &RsaPublicKey) -> bool {
+    decode_ciphertext(data).is_some_and(|bytes| bytes.len() == public_key.size())
+}
+
+/// Append an object key or array index to a JSON Pointer (RFC 6901), escaping `~` and `/`.
+pub fn pointer_push(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// JSON Pointers of every string in `data` that looks like a ciphertext made with `public_key`.
+pub fn ciphertext_paths(data: &Value, public_key: &RsaPublicKey) -> Vec<String> {
+    fn walk(data: &Value, public_key: &RsaPublicKey, path: String, found: &mut Vec<String>) {
+        match data {
+            Value::String(string) if looks_encrypted(string, public_key) => found.push(path),
+            Value::Array(vec) => {
+                for (index, entry) in vec.iter().enumerate() {
+                    walk(
+                        entry,
+                        public_key,
+                        pointer_push(&path, &index.to_string()),
+                        found,
+                    );
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map {
+                    walk(value, public_key, pointer_push(&path, key), found);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut found = vec![];
+    walk(data, public_key, String::new(), &mut found);
+    found
+}
+
+/// Encrypt a slice of bytes using the public key.
+pub fn encrypt_pub_slice(pub_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>, String> {
+    Ok(pub_key
+        .encrypt(&mut OsRng, PaddingScheme::new_pkcs1v15_encrypt(), data)
+        .map_err(|_| "failed to encrypt")?)
+}
+
+/// Encrypt and then base64 encode a string using the public key.
+pub fn encrypt_pub_string(pub_key: &RsaPublicKey, data: &str) -> Result<String, String> {
+    Ok(encode_ciphertext(&encrypt_pub_slice(
+        pub_key,
+        data.as_bytes(),
+    )?))
+}
+
+/// What the RSA block of an encrypted field starts with, a format version: the field is bound to its JSON
+/// Pointer, so it only decrypts where it was encrypted. Fields encrypted before are the bare JSON of the value,
+/// which never starts with it.
+pub const BOUND_FIELD_PREFIX: &[u8] = b"\x01";
+
+/// What `encrypt_field` encrypts for the value at the JSON Pointer `path`.
+pub fn field_plaintext(path: &str, value: &Value) -> Zeroizing<Vec<u8>> {
+    let json = Zeroizing::new(
+        serde_json::to_vec(&(path, value)).expect("a path and a value always serialize"),
+    );
+    Zeroizing::new([BOUND_FIELD_PREFIX, &json].concat())
+}
+
+/// Encrypt and base64 encode the value at the JSON Pointer `path` of a document.
+pub fn encrypt_field(pub_key: &RsaPublicKey, path: &str, value: &Value) -> Result<String, String> {
+    Ok(encode_ciphertext(&encrypt_pub_slice(
+        pub_key,
+        &field_plaintext(path, value),
+    )?))
+}
+
+/// The JSON Pointer a decrypted field block is bound to, `None` for a field encrypted before fields were bound,
+/// and its value. `None` if the block is not a field.
+pub fn decode_field(block: &[u8]) -> Option<(Option<String>, Value)> {
+    match block.strip_prefix(BOUND_FIELD_PREFIX) {
+        Some(bound) => serde_json::from_slice::<(String, Value)>(bound)
+            .ok()
+            .map(|(path, value)| (Some(path), value)),
+        None => serde_json::from_slice(block)
+            .ok()
+            .map(|value| (None, value)),
+    }
+}
+
+/// What the RSA block of a wrapped data key starts with. Field plaintexts are JSON, which never starts with a
+/// zero byte, so a field ciphertext never unwraps as a key and a wrapped key never decrypts as a field.
+pub const WRAPPED_KEY_PREFIX: &[u8] = b"\0wrapped key\0";
+
+/// The largest data key `wrap_data_key` fits in one block for `pub_key`.
+pub fn max_data_key_size(pub_key: &RsaPublicKey) -> usize {
+    pub_key.size() - 11 - WRAPPED_KEY_PREFIX.len()
+}
+
+/// Wrap a data key with the public key: one RSA PKCS#1 v1.5 block of `WRAPPED_KEY_PREFIX` and the key.
+pub fn wrap_data_key(pub_key: &RsaPublicKey, data_key: &[u8]) -> Result<Vec<u8>, String> {
+    let max = max_data_key_size(pub_key);
+    if data_key.len() > max {
+        return Err(format!("data keys can be at most {} bytes", max));
+    }
+    let block = Zeroizing::new([WRAPPED_KEY_PREFIX, data_key].concat());
+    encrypt_pub_slice(pub_key, &block)
+}
+
+/// The data key in a decrypted wrapped key block, if the block is one.
+pub fn unwrapped_data_key(block: &[u8]) -> Option<&[u8]> {
+    block.strip_prefix(WRAPPED_KEY_PREFIX)
+}
+
+/// Takes a serde_json::Value and encrypts it using the public key on every key specified in `fields`.
+pub fn detect_and_encrypt(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    fields: &[String],
+) -> Result<Value, String> {
+    let actions = fields
+        .iter()
+        .map(|field| (field.clone(), FieldAction::Encrypt))
+        .collect();
+    detect_and_apply(payload, public_key, &actions, &mut |_| {
+        Err("no tokenization".to_string())
+    })
+}
+
+/// The canonical bytes a signature is computed over: the compact JSON serialization of the plaintext payload.
+pub fn signing_input(payload: &Value) -> Vec<u8> {
+    payload.to_string().into_bytes()
+}
+
+/// SHA256 of the canonical signing input.
+pub fn signing_digest(payload: &Value) -> Vec<u8> {
+    digest(Algorithm::SHA256, &Zeroizing::new(signing_input(payload)))
+}
+
+/// Check a base64 encoded signature over a plaintext payload using only the public key.
+pub fn verify_signature(payload: &Value, signature: &str, public_key: &RsaPublicKey) -> bool {
+    base64::decode(signature)
+        .map(|signature| {
+            public_key
+                .verify(
+                    PaddingScheme::new_pkcs1v15_sign(None),
+                    &signing_digest(payload),
+                    &signature,
+                )
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// The string an HMAC authenticated request signs: method, path with query, unix timestamp and the hex SHA256 of
+/// the body, one per line.
+pub fn request_signing_input(method: &str, path: &str, timestamp: u64, body: &[u8]) -> String {
+    request_signing_input_with_hash(method, path, timestamp, &sha256_hex(body))
+}
+
+/// `request_signing_input` given the hex SHA256 of the body, for bodies hashed as they are streamed.
+pub fn request_signing_input_with_hash(
+    method: &str,
+    path: &str,
+    timestamp: u64,
+    body_sha256: &str,
+) -> String {
+    format!("{}\n{}\n{}\n{}", method, path, timestamp, body_sha256)
+}
+
+/// Lowercase hex SHA256 of some bytes.
+pub fn sha256_hex(data: &[u8]) -> String {
+    digest(Algorithm::SHA256, data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,236 @@
+//! Detection of personal data in string values, for fields that should have been configured to be encrypted.
+//!
+//! A string is personal data when it is, or has a few words in a row that are, an email address, a card
+//! number passing the Luhn check, an IBAN passing its check digits, a US Social Security or UK National
+//! Insurance number, or an international (`+` prefixed) or ten digit phone number. Numbers are not scanned:
+//! too many identifiers and timestamps would look like card numbers.
+
+use crate::{looks_encrypted, pointer_push};
+use rsa::RsaPublicKey;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A kind of personal data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pii {
+    Email,
+    CardNumber,
+    Iban,
+    NationalId,
+    PhoneNumber,
+}
+
+impl Pii {
+    /// The name of the kind in reports.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pii::Email => "email",
+            Pii::CardNumber => "cardNumber",
+            Pii::Iban => "iban",
+            Pii::NationalId => "nationalId",
+            Pii::PhoneNumber => "phoneNumber",
+        }
+    }
+}
+
+/// Words joined to look for personal data written in groups, such as `4111 1111 1111 1111` in a sentence.
+const MAX_WORDS: usize = 8;
+
+/// The kind of personal data in `text`, if any.
+pub fn detect(text: &str) -> Option<Pii> {
+    let text = text.trim();
+    let words = text
+        .split_whitespace()
+        .map(|word| {
+            word.trim_start_matches(['(', '<', '[', '"', '\''])
+                .trim_end_matches([')', '>', ']', '"', '\'', ',', ';', ':', '.', '!', '?'])
+        })
+        .collect::<Vec<_>>();
+    detect_word(text).or_else(|| {
+        (0..words.len()).find_map(|start| {
+            (start + 1..=words.len().min(start + MAX_WORDS))
+                .find_map(|end| detect_word(&words[start..end].join(" ")))
+        })
+    })
+}
+
+fn detect_word(text: &str) -> Option<Pii> {
+    if is_email(text) {
+        Some(Pii::Email)
+    } else if is_card_number(text) {
+        Some(Pii::CardNumber)
+    } else if is_iban(text) {
+        Some(Pii::Iban)
+    } else if is_ssn(text) || is_nino(text) {
+        Some(Pii::NationalId)
+    } else if is_phone_number(text) {
+        Some(Pii::PhoneNumber)
+    } else {
+        None
+    }
+}
+
+/// The personal data in the strings of `payload` that are not ciphertexts, by JSON Pointer of the property
+/// holding them: a string in an array is reported with the property holding the array, which is what
+/// `detect_and_encrypt` can encrypt. Properties named for which `skip` holds are not searched.
+pub fn find_pii(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    skip: &dyn Fn(&str) -> bool,
+) -> BTreeMap<String, Pii> {
+    let mut found = BTreeMap::new();
+    visit(payload, "", "", public_key, skip, &mut found);
+    found
+}
+
+fn visit(
+    data: &Value,
+    path: &str,
+    property: &str,
+    public_key: &RsaPublicKey,
+    skip: &dyn Fn(&str) -> bool,
+    found: &mut BTreeMap<String, Pii>,
+) {
+    match data {
+        Value::Object(map) => {
+            for (key, value) in map.iter().filter(|(key, _)| !skip(key)) {
+                let path = pointer_push(path, key);
+                visit(value, &path, &path, public_key, skip, found);
+            }
+        }
+        Value::Array(vec) => {
+            for (index, value) in vec.iter().enumerate() {
+                let path = pointer_push(path, &index.to_string());
+                visit(value, &path, property, public_key, skip, found);
+            }
+        }
+        Value::String(string) if !property.is_empty() && !looks_encrypted(string, public_key) => {
+            if let Some(pii) = detect(string) {
+                found.entry(property.to_string()).or_insert(pii);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn is_email(text: &str) -> bool {
+    let Some((local, domain)) = text.split_once('@') else {
+        return false;
+    };
+    let labels = domain.split('.').collect::<Vec<_>>();
+    (1..=64).contains(&local.len())
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".!#$%&'*+/=?^_`{|}~-".contains(c))
+        && labels.len() >= 2
+        && labels.iter().all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()))
+}
+
+/// 13 to 19 digits, maybe grouped with spaces or dashes, passing the Luhn check.
+fn is_card_number(text: &str) -> bool {
+    if !text
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ' ' || c == '-')
+    {
+        return false;
+    }
+    let digits = text
+        .chars()
+        .filter_map(|c| c.to_digit(10))
+        .collect::<Vec<_>>();
+    (13..=19).contains(&digits.len())
+        && digits
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(index, &digit)| match index % 2 {
+                0 => digit,
+                _ if digit > 4 => digit * 2 - 9,
+                _ => digit * 2,
+            })
+            .sum::<u32>()
+            % 10
+            == 0
+}
+
+/// A country code, two check digits and up to 30 letters or digits, maybe grouped with spaces, whose
+/// number is 1 modulo 97.
+fn is_iban(text: &str) -> bool {
+    let compact = text.chars().filter(|c| *c != ' ').collect::<Vec<_>>();
+    if !(15..=34).contains(&compact.len())
+        || !compact[..2].iter().all(char::is_ascii_uppercase)
+        || !compact[2..4].iter().all(char::is_ascii_digit)
+        || !compact
+            .iter()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+    {
+        return false;
+    }
+    compact[4..]
+        .iter()
+        .chain(&compact[..4])
+        .fold(0, |remainder, c| {
+            // letters count as two digits, A as 10
+            let value = c.to_digit(36).unwrap_or_default();
+            let shift = if value < 10 { 10 } else { 100 };
+            (remainder * shift + value) % 97
+        })
+        == 1
+}
+
+/// A US Social Security number, `123-45-6789`, of an area, group and serial that are given out.
+fn is_ssn(text: &str) -> bool {
+    let parts = text.split('-').collect::<Vec<_>>();
+    matches!(parts.as_slice(), [area, group, serial]
+        if area.len() == 3 && group.len() == 2 && serial.len() == 4
+            && [area, group, serial].iter().all(|part| part.chars().all(|c| c.is_ascii_digit()))
+            && !["000", "666"].contains(area) && !area.starts_with('9')
+            && *group != "00" && *serial != "0000")
+}
+
+/// A UK National Insurance number, `AB123456C`, maybe grouped with spaces.
+fn is_nino(text: &str) -> bool {
+    let compact = text.chars().filter(|c| *c != ' ').collect::<Vec<_>>();
+    compact.len() == 9
+        && compact[0].is_ascii_uppercase()
+        && !"DFIQUV".contains(compact[0])
+        && compact[1].is_ascii_uppercase()
+        && !"DFIOQUV".contains(compact[1])
+        && compact[2..8].iter().all(char::is_ascii_digit)
+        && ('A'..='D').contains(&compact[8])
+}
+
+/// `+` and 8 to 15 digits, or 10 digits grouped as `(555) 123-4567`, `555-123-4567` or `555.123.4567`.
+fn is_phone_number(text: &str) -> bool {
+    let (international, number) = match text.strip_prefix('+') {
+        Some(number) => (true, number),
+        None => (false, text),
+    };
+    if number.is_empty()
+        || !number
+            .chars()
+            .all(|c| c.is_ascii_digit() || " -.()".contains(c))
+    {
+        return false;
+    }
+    let groups = number
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|group| !group.is_empty())
+        .map(str::len)
+        .collect::<Vec<_>>();
+    let digits = groups.iter().sum::<usize>();
+    match international {
+        true => (8..=15).contains(&digits),
+        false => groups == [3, 3, 4],
+    }
+}
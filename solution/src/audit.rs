@@ -0,0 +1,237 @@
+//! Audit log of the operations done by the server. Every request to a route other than `/` appends one JSON
+//! line to the log file with the caller, endpoint, tenant, key id, the field paths the operation touched and
+//! its outcome. Values of fields are never logged.
+//!
+//! Each entry holds the SHA256 of the previous line in `previousHash`, so editing, removing or reordering
+//! past entries breaks the chain, which `verify_chain` detects.
+
+use crate::auth::Caller;
+use crate::policy::TENANT_HEADER;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{Error, HttpRequest};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `previousHash` of the first entry of a log.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// Unix seconds.
+    pub timestamp: u64,
+    /// Id of the authenticated caller, `None` when authentication is disabled or failed.
+    pub caller: Option<String>,
+    pub endpoint: String,
+    pub tenant: Option<String>,
+    /// Fingerprint of the server key used.
+    pub key_id: String,
+    /// JSON Pointers of the fields encrypted or decrypted, or the new field list for `/config`.
+    pub fields: Vec<String>,
+    /// HTTP status of the response.
+    pub status: u16,
+    pub success: bool,
+    pub previous_hash: String,
+}
+
+/// Fields touched by a handler, stored in the request extensions for the audit log.
+pub struct AuditedFields(pub Vec<String>);
+
+/// Record the fields touched by the request being handled.
+pub fn record_fields(req: &HttpRequest, fields: Vec<String>) {
+    req.extensions_mut().insert(AuditedFields(fields));
+}
+
+/// JSON Pointers of the ciphertexts in either document whose value differs in the other one:
+/// the fields encrypted going from `before` to `after`, or decrypted.
+pub fn changed_ciphertexts(
+    before: &Value,
+    after: &Value,
+    public_key: &rsa::RsaPublicKey,
+) -> Vec<String> {
+    let mut paths = field_crypto::ciphertext_paths(before, public_key);
+    paths.extend(field_crypto::ciphertext_paths(after, public_key));
+    paths.sort();
+    paths.dedup();
+    paths.retain(|path| before.pointer(path) != after.pointer(path));
+    paths
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// An append-only audit log file.
+pub struct AuditLog {
+    key_id: String,
+    /// The file and the hash of its last line.
+    file: Mutex<(File, String)>,
+}
+
+impl AuditLog {
+    /// Open the log for appending, continuing the hash chain of the existing entries.
+    pub fn open<T: AsRef<Path>>(file: T, key_id: String) -> Result<AuditLog, String> {
+        let last_hash = match fs::read_to_string(&file) {
+            Ok(data) => data
+                .lines()
+                .last()
+                .map(|line| sha256_hex(line.as_bytes()))
+                .unwrap_or_else(|| GENESIS_HASH.to_string()),
+            Err(_) => GENESIS_HASH.to_string(),
+        };
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file)
+            .map_err(|e| format!("failed to open {}: {}", file.as_ref().display(), e))?;
+        Ok(AuditLog {
+            key_id,
+            file: Mutex::new((log, last_hash)),
+        })
+    }
+
+    /// Append an entry, filling in the key id and chain hash.
+    fn append(&self, mut entry: Entry) {
+        let mut file = self.file.lock().unwrap();
+        entry.key_id = self.key_id.clone();
+        entry.previous_hash = file.1.clone();
+        let line = serde_json::to_string(&entry).expect("audit entries serialize");
+        // an operation that cannot be recorded is still answered, but loudly
+        match writeln!(file.0, "{}", line) {
+            Ok(()) => file.1 = sha256_hex(line.as_bytes()),
+            Err(e) => eprintln!("Failed to write audit log: {}", e),
+        }
+    }
+}
+
+/// Check the hash chain of an audit log file. Returns the number of entries, or the first broken line.
+pub fn verify_chain<T: AsRef<Path>>(file: T) -> Result<usize, String> {
+    let data = fs::read_to_string(&file)
+        .map_err(|e| format!("failed to read {}: {}", file.as_ref().display(), e))?;
+    let mut previous = GENESIS_HASH.to_string();
+    for (index, line) in data.lines().enumerate() {
+        let entry: Entry = serde_json::from_str(line)
+            .map_err(|e| format!("line {} is not an audit entry: {}", index + 1, e))?;
+        if entry.previous_hash != previous {
+            return Err(format!(
+                "line {} does not follow the previous entry",
+                index + 1
+            ));
+        }
+        previous = sha256_hex(line.as_bytes());
+    }
+    Ok(data.lines().count())
+}
+
+/// Middleware factory logging every request but `/`. Does nothing without a log.
+#[derive(Clone)]
+pub struct Audit {
+    log: Option<Arc<AuditLog>>,
+}
+
+impl Audit {
+    pub fn new(log: Option<AuditLog>) -> Self {
+        Audit {
+            log: log.map(Arc::new),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Audit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuditMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            log: self.log.clone(),
+        })
+    }
+}
+
+pub struct AuditMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    log: Option<Arc<AuditLog>>,
+}
+
+impl<S, B> Service for AuditMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let log = match &self.log {
+            Some(log) if req.path() != "/" => log.clone(),
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        let endpoint = req.path().to_string();
+        let tenant = req
+            .headers()
+            .get(TENANT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let response = self.service.borrow_mut().call(req);
+        Box::pin(async move {
+            let response = response.await;
+            let mut entry = Entry {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs()),
+                caller: None,
+                endpoint,
+                tenant,
+                key_id: String::new(),
+                fields: vec![],
+                status: 0,
+                success: false,
+                previous_hash: String::new(),
+            };
+            match &response {
+                Ok(response) => {
+                    let extensions = response.request().extensions();
+                    entry.caller = extensions.get::<Caller>().map(|caller| caller.id.clone());
+                    if let Some(AuditedFields(fields)) = extensions.get::<AuditedFields>() {
+                        entry.fields = fields.clone();
+                    }
+                    entry.status = response.status().as_u16();
+                    entry.success = response.status().is_success();
+                }
+                // rejected by the authentication middleware
+                Err(e) => entry.status = e.as_response_error().status_code().as_u16(),
+            }
+            log.append(entry);
+            response
+        })
+    }
+}
@@ -0,0 +1,476 @@
+//! Authentication middleware. Every route except `/` needs credentials accepted by one of the configured
+//! `Authenticator`s, and the authenticated `Caller` must be allowed the route by the `Policy`.
+//! The caller and its `Grant` are stored in the request extensions for the handlers.
+
+use crate::policy::{Policy, TENANT_HEADER};
+use crate::tls::PeerSubject;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorForbidden, ErrorPayloadTooLarge, ErrorUnauthorized, PayloadError};
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header carrying a static API key.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+/// Headers of an HMAC signed request, see `field_crypto::request_signing_input`.
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Optional hex SHA256 of the body of an HMAC signed request. With it the body is checked as the handler
+/// streams it rather than read up front, so it may be larger than the body limit.
+pub const CONTENT_SHA256_HEADER: &str = "X-Content-Sha256";
+
+/// Route patterns answering with plaintext streamed out as the body comes in. Their signed bodies are read up
+/// front whatever `CONTENT_SHA256_HEADER` says, as a mismatch at the end of the body would come after the
+/// plaintext of another body went out.
+const PLAINTEXT_STREAMS: &[&str] = &["/decrypt/blob"];
+
+/// How far, in seconds, the timestamp of a signed request may be from the server's clock.
+const MAX_CLOCK_SKEW: u64 = 300;
+
+/// An operation a caller may be allowed to do.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Encrypt,
+    Decrypt,
+    Sign,
+    Verify,
+    Config,
+    /// Wrap data keys and generate wrapped data keys.
+    Wrap,
+    Unwrap,
+    /// Move fields from retired keys to the current key.
+    Reencrypt,
+    Tokenize,
+    Detokenize,
+}
+
+impl Permission {
+    /// The permission needed to call the route of resource pattern `pattern`, as registered in `main.rs`.
+    /// Patterns without one are refused.
+    pub fn for_pattern(pattern: &str) -> Option<Permission> {
+        match pattern {
+            "/encrypt" | "/encrypt/blob" | "/pii" => Some(Permission::Encrypt),
+            "/decrypt" | "/decrypt/blob" => Some(Permission::Decrypt),
+            "/sign" | "/sign/raw" => Some(Permission::Sign),
+            "/verify" | "/verify/raw" => Some(Permission::Verify),
+            "/config" | "/schemas/{name}" => Some(Permission::Config),
+            "/datakey" | "/keys/{id}/wrap" => Some(Permission::Wrap),
+            "/keys/{id}/unwrap" => Some(Permission::Unwrap),
+            "/reencrypt" => Some(Permission::Reencrypt),
+            "/tokenize" => Some(Permission::Tokenize),
+            "/detokenize" => Some(Permission::Detokenize),
+            _ => None,
+        }
+    }
+}
+
+/// An authenticated identity and what it may do.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    pub id: String,
+    pub permissions: Vec<Permission>,
+    /// Roles in the policy file.
+    pub roles: Vec<String>,
+}
+
+/// One entry of the credentials file. A credential may have an API key, an HMAC secret, or both.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Credential {
+    pub id: String,
+    /// Hex SHA256 of the API key, so the file does not hold the key itself.
+    #[serde(default)]
+    pub api_key_sha256: Option<String>,
+    #[serde(default)]
+    pub hmac_secret: Option<String>,
+    /// Subject of the client certificate identifying this caller over TLS, e.g. `C=US, O=Acme, CN=producer`.
+    #[serde(default)]
+    pub client_certificate_subject: Option<String>,
+    /// Endpoints allowed for any tenant and every field.
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    /// Roles of the policy file, for restricted access.
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl Credential {
+    fn caller(&self) -> Caller {
+        Caller {
+            id: self.id.clone(),
+            permissions: self.permissions.clone(),
+            roles: self.roles.clone(),
+        }
+    }
+}
+
+/// A way of authenticating requests.
+pub trait Authenticator: Send + Sync {
+    /// Whether the request carries credentials for this scheme.
+    fn applies(&self, req: &ServiceRequest) -> bool;
+
+    /// Whether `authenticate` needs the hash of the request body.
+    fn needs_body(&self) -> bool {
+        false
+    }
+
+    /// Check the credentials of a request this authenticator applies to. `body_sha256` is the hex SHA256 of the
+    /// body, empty unless `needs_body`.
+    fn authenticate(&self, req: &ServiceRequest, body_sha256: &str) -> Result<Caller, String>;
+}
+
+fn header<'a>(req: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    sha256_hex_digest(&Sha256::digest(data))
+}
+
+fn sha256_hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Static API keys sent in the `X-Api-Key` header.
+pub struct ApiKeyAuthenticator {
+    /// Callers by hex SHA256 of their key.
+    callers: HashMap<String, Caller>,
+}
+
+impl ApiKeyAuthenticator {
+    pub fn new(credentials: &[Credential]) -> Self {
+        ApiKeyAuthenticator {
+            callers: credentials
+                .iter()
+                .filter_map(|credential| {
+                    credential
+                        .api_key_sha256
+                        .as_ref()
+                        .map(|hash| (hash.to_lowercase(), credential.caller()))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for ApiKeyAuthenticator {
+    fn applies(&self, req: &ServiceRequest) -> bool {
+        req.headers().contains_key(API_KEY_HEADER)
+    }
+
+    fn authenticate(&self, req: &ServiceRequest, _body_sha256: &str) -> Result<Caller, String> {
+        let key = header(req, API_KEY_HEADER).ok_or("invalid api key")?;
+        self.callers
+            .get(&sha256_hex(key.as_bytes()))
+            .cloned()
+            .ok_or_else(|| "invalid api key".to_string())
+    }
+}
+
+/// Requests signed with a shared secret: `X-Signature` is the base64 HMAC-SHA256 of
+/// `field_crypto::request_signing_input`, for the client in `X-Client-Id` at the time in `X-Timestamp`.
+pub struct HmacAuthenticator {
+    /// Secret and caller by client id.
+    clients: HashMap<String, (Vec<u8>, Caller)>,
+}
+
+impl HmacAuthenticator {
+    pub fn new(credentials: &[Credential]) -> Self {
+        HmacAuthenticator {
+            clients: credentials
+                .iter()
+                .filter_map(|credential| {
+                    credential.hmac_secret.as_ref().map(|secret| {
+                        (
+                            credential.id.clone(),
+                            (secret.as_bytes().to_vec(), credential.caller()),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for HmacAuthenticator {
+    fn applies(&self, req: &ServiceRequest) -> bool {
+        req.headers().contains_key(SIGNATURE_HEADER)
+    }
+
+    fn needs_body(&self) -> bool {
+        true
+    }
+
+    fn authenticate(&self, req: &ServiceRequest, body_sha256: &str) -> Result<Caller, String> {
+        let client_id = header(req, CLIENT_ID_HEADER).ok_or("missing client id")?;
+        let timestamp: u64 = header(req, TIMESTAMP_HEADER)
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or("missing or invalid timestamp")?;
+        let signature = header(req, SIGNATURE_HEADER)
+            .and_then(|signature| base64::decode(signature).ok())
+            .ok_or("invalid signature")?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0);
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW {
+            return Err("request timestamp too far from server time".to_string());
+        }
+        let (secret, caller) = self.clients.get(client_id).ok_or("invalid signature")?;
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or_else(|| req.path());
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).map_err(|_| "invalid signature")?;
+        mac.update(
+            field_crypto::request_signing_input_with_hash(
+                req.method().as_str(),
+                path,
+                timestamp,
+                body_sha256,
+            )
+            .as_bytes(),
+        );
+        mac.verify_slice(&signature)
+            .map_err(|_| "invalid signature")?;
+        Ok(caller.clone())
+    }
+}
+
+/// Verified TLS client certificates, identified by their subject.
+pub struct ClientCertificateAuthenticator {
+    callers: HashMap<String, Caller>,
+}
+
+impl ClientCertificateAuthenticator {
+    pub fn new(credentials: &[Credential]) -> Self {
+        ClientCertificateAuthenticator {
+            callers: credentials
+                .iter()
+                .filter_map(|credential| {
+                    credential
+                        .client_certificate_subject
+                        .as_ref()
+                        .map(|subject| (subject.clone(), credential.caller()))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for ClientCertificateAuthenticator {
+    fn applies(&self, req: &ServiceRequest) -> bool {
+        req.extensions()
+            .get::<PeerSubject>()
+            .is_some_and(|subject| subject.0.is_some())
+    }
+
+    fn authenticate(&self, req: &ServiceRequest, _body_sha256: &str) -> Result<Caller, String> {
+        let extensions = req.extensions();
+        let subject = extensions
+            .get::<PeerSubject>()
+            .and_then(|subject| subject.0.as_ref())
+            .ok_or("missing client certificate")?;
+        self.callers
+            .get(subject)
+            .cloned()
+            .ok_or_else(|| format!("unknown client certificate {}", subject))
+    }
+}
+
+/// Read the credentials file (a JSON array of `Credential`) and build an authenticator for each scheme.
+pub fn load_credentials<T: AsRef<Path>>(file: T) -> Result<Vec<Box<dyn Authenticator>>, String> {
+    let data = fs::read_to_string(&file)
+        .map_err(|e| format!("failed to read {}: {}", file.as_ref().display(), e))?;
+    let credentials: Vec<Credential> = serde_json::from_str(&data)
+        .map_err(|e| format!("invalid credentials in {}: {}", file.as_ref().display(), e))?;
+    Ok(vec![
+        Box::new(ApiKeyAuthenticator::new(&credentials)),
+        Box::new(HmacAuthenticator::new(&credentials)),
+        // explicit credentials in the headers take precedence over the connection's certificate
+        Box::new(ClientCertificateAuthenticator::new(&credentials)),
+    ])
+}
+
+/// Middleware factory. With no authenticators every request is let through.
+#[derive(Clone)]
+pub struct Authentication {
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+    policy: Arc<Policy>,
+    body_limit: usize,
+    plaintext_stream_limit: usize,
+}
+
+impl Authentication {
+    /// `body_limit` bounds the body read for authenticators that need it, as the route extractors do, and
+    /// `plaintext_stream_limit` the body read for them on the routes of `PLAINTEXT_STREAMS`.
+    pub fn new(
+        authenticators: Vec<Box<dyn Authenticator>>,
+        policy: Policy,
+        body_limit: usize,
+        plaintext_stream_limit: usize,
+    ) -> Self {
+        Authentication {
+            authenticators: Arc::new(authenticators),
+            policy: Arc::new(policy),
+            body_limit,
+            plaintext_stream_limit,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Authentication
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthenticationMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthenticationMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            authenticators: self.authenticators.clone(),
+            policy: self.policy.clone(),
+            body_limit: self.body_limit,
+            plaintext_stream_limit: self.plaintext_stream_limit,
+        })
+    }
+}
+
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+    policy: Arc<Policy>,
+    body_limit: usize,
+    plaintext_stream_limit: usize,
+}
+
+/// Read the whole body, up to `limit` bytes, and put it back so the handler can still read it.
+async fn take_body(req: &mut ServiceRequest, limit: usize) -> Result<Bytes, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(ErrorPayloadTooLarge("payload too large"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let replay = body.clone();
+    req.set_payload(Payload::Stream(Box::pin(stream::once(async move {
+        Ok(replay)
+    }))));
+    Ok(body)
+}
+
+/// Pass the body on to the handler as it arrives, failing at its end unless it hashes to `expected`, so the
+/// handler never completes on a body other than the one authenticated.
+fn check_body_hash(req: &mut ServiceRequest, expected: String) {
+    let hasher = Rc::new(RefCell::new(Sha256::new()));
+    let hashing = hasher.clone();
+    let body = req.take_payload().map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            hashing.borrow_mut().update(chunk);
+        }
+        chunk
+    });
+    let check = stream::once(async move {
+        let actual = hasher.replace(Sha256::new()).finalize();
+        if sha256_hex_digest(&actual) == expected {
+            Ok(Bytes::new())
+        } else {
+            Err(PayloadError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "body does not match X-Content-Sha256",
+            )))
+        }
+    });
+    req.set_payload(Payload::Stream(Box::pin(body.chain(check))));
+}
+
+impl<S, B> Service for AuthenticationMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let authenticators = self.authenticators.clone();
+        let policy = self.policy.clone();
+        let body_limit = self.body_limit;
+        let plaintext_stream_limit = self.plaintext_stream_limit;
+        Box::pin(async move {
+            if !authenticators.is_empty() && req.path() != "/" {
+                let authenticator = authenticators
+                    .iter()
+                    .find(|authenticator| authenticator.applies(&req))
+                    .ok_or_else(|| ErrorUnauthorized("missing credentials"))?;
+                // the resource the router picks, from the percent-decoded path it matches, not the raw one
+                let pattern = req.resource_map().match_pattern(req.match_info().path());
+                let streamed_hash = header(&req, CONTENT_SHA256_HEADER).map(str::to_lowercase);
+                let body_sha256 = match streamed_hash {
+                    _ if !authenticator.needs_body() => String::new(),
+                    _ if pattern
+                        .as_deref()
+                        .is_some_and(|pattern| PLAINTEXT_STREAMS.contains(&pattern)) =>
+                    {
+                        sha256_hex(&take_body(&mut req, plaintext_stream_limit).await?)
+                    }
+                    Some(hash) => {
+                        check_body_hash(&mut req, hash.clone());
+                        hash
+                    }
+                    None => sha256_hex(&take_body(&mut req, body_limit).await?),
+                };
+                let caller = authenticator
+                    .authenticate(&req, &body_sha256)
+                    .map_err(ErrorUnauthorized)?;
+                let permission = pattern
+                    .as_deref()
+                    .and_then(Permission::for_pattern)
+                    .ok_or_else(|| ErrorForbidden("no permission allows this path"))?;
+                let grant = policy
+                    .authorize(&caller, permission, header(&req, TENANT_HEADER))
+                    .map_err(ErrorForbidden)?;
+                req.extensions_mut().insert(caller);
+                req.extensions_mut().insert(grant);
+            }
+            // do not hold the borrow across the await, other requests on this worker need the service
+            let response = service.borrow_mut().call(req);
+            response.await
+        })
+    }
+}
@@ -0,0 +1,679 @@
+//! Command line access to the encryption server, or to the same operations done locally with the key files
+//! written by `load_keys`. Documents are read as JSON from a file, or from stdin when no file is given.
+
+use clap::{ArgEnum, Parser, Subcommand};
+use field_crypto::blob::{BlobDecryptor, BlobEncryptor, BlobStream};
+use field_crypto::format::Format;
+use interview_problem::envelope::{Envelope, EnvelopeSettings, NonceStore};
+use interview_problem::provider::{self, KeyProvider, SoftwareKey};
+use interview_problem::{audit, crypto, keys};
+use rand::rngs::OsRng;
+use rsa::PublicKeyParts;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use zeroize::Zeroizing;
+
+#[derive(Parser)]
+#[clap(
+    name = "crypt",
+    about = "Encrypt, decrypt, sign and verify JSON documents"
+)]
+struct Args {
+    /// Base url of a running server (e.g. http://localhost:8080). Operations are done locally if omitted.
+    #[clap(long, global = true)]
+    server: Option<String>,
+    /// API key for a server with authentication enabled.
+    #[clap(long, global = true, env = "CRYPT_API_KEY")]
+    api_key: Option<String>,
+    /// Client id for HMAC signed requests, used with --hmac-secret.
+    #[clap(long, global = true, env = "CRYPT_CLIENT_ID", requires = "hmac-secret")]
+    client_id: Option<String>,
+    /// Shared secret for HMAC signed requests.
+    #[clap(long, global = true, env = "CRYPT_HMAC_SECRET", requires = "client-id")]
+    hmac_secret: Option<String>,
+    /// PEM CA certificate to trust for an https server.
+    #[clap(long, global = true)]
+    ca_certificate: Option<PathBuf>,
+    /// PKCS#12 archive with the client certificate for a server verifying them.
+    #[clap(long, global = true)]
+    identity: Option<PathBuf>,
+    /// Password of the --identity archive.
+    #[clap(
+        long,
+        global = true,
+        env = "CRYPT_IDENTITY_PASSWORD",
+        default_value = ""
+    )]
+    identity_password: String,
+    /// Public key file for local operations.
+    #[clap(long, global = true, default_value = "pub_key")]
+    public_key: PathBuf,
+    /// Private key file for local operations.
+    #[clap(long, global = true, default_value = "priv_key")]
+    private_key: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Encrypt the selected fields of a document.
+    Encrypt {
+        /// Field to encrypt, may be repeated. Only for local operation, a server uses its own configuration.
+        #[clap(long = "field")]
+        fields: Vec<String>,
+        /// Format of the document, from the file extension by default.
+        #[clap(long, arg_enum)]
+        format: Option<DocumentFormat>,
+        file: Option<PathBuf>,
+    },
+    /// Decrypt every encrypted string of a document.
+    Decrypt {
+        /// Format of the document, from the file extension by default.
+        #[clap(long, arg_enum)]
+        format: Option<DocumentFormat>,
+        file: Option<PathBuf>,
+    },
+    /// Print the signature of a plaintext document.
+    Sign {
+        /// Sign each field on its own, printing `{"signatures": {<JSON Pointer>: ...}}`.
+        #[clap(long, conflicts_with = "envelope")]
+        per_field: bool,
+        /// Sign in an envelope with an expiry and a nonce, printing `{"signature": ..., "envelope": ...}`.
+        #[clap(long)]
+        envelope: bool,
+        /// Lifetime of the envelope in seconds.
+        #[clap(long, requires = "envelope")]
+        ttl: Option<u64>,
+        /// Who may verify the envelope.
+        #[clap(long, requires = "envelope")]
+        audience: Option<String>,
+        file: Option<PathBuf>,
+    },
+    /// Check a `{"signature": ..., "data": ...}` or `{"signatures": {...}, "data": ...}` document, with an
+    /// `envelope` if signed in one. Exits with 1 if the signature, or any field signature, does not match.
+    Verify {
+        /// The verifier, for envelopes signed for an audience.
+        #[clap(long)]
+        audience: Option<String>,
+        file: Option<PathBuf>,
+    },
+    /// Print the signature of any file, in the same format as `sign`.
+    SignRaw { file: Option<PathBuf> },
+    /// Check the signature of any file. Exits with 1 if it does not match.
+    VerifyRaw {
+        #[clap(long)]
+        signature: String,
+        file: Option<PathBuf>,
+    },
+    /// Encrypt any file into a blob (see `field_crypto::blob`).
+    EncryptBlob {
+        /// Where to write the blob.
+        #[clap(long, short)]
+        output: PathBuf,
+        file: Option<PathBuf>,
+    },
+    /// Decrypt a blob made by `encrypt-blob`.
+    DecryptBlob {
+        /// Where to write the content.
+        #[clap(long, short)]
+        output: PathBuf,
+        file: Option<PathBuf>,
+    },
+    /// Generate the key files if they do not exist yet, like the server does on first start.
+    Keygen,
+    /// List the fields of a document that look encrypted with the public key.
+    Inspect { file: Option<PathBuf> },
+    /// Check the hash chain of a server audit log. Exits with 1 if an entry was altered, removed or reordered.
+    VerifyAudit { file: PathBuf },
+    /// Hold the private key for a server configured with an agent key, serving it on a new Unix socket.
+    Agent { socket: PathBuf },
+    /// Manage the key files without starting the server.
+    Keys {
+        #[clap(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// Generate a new key pair and write it to the key files.
+    Generate {
+        #[clap(long = "type", arg_enum, default_value = "rsa")]
+        key_type: KeyType,
+        /// Key size in bits.
+        #[clap(long, default_value = "2048")]
+        bits: usize,
+        /// Overwrite existing key files.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Import an RSA private key (PKCS#8 or PKCS#1, PEM or DER) and write the key files in the server's format.
+    Import {
+        file: Option<PathBuf>,
+        /// Overwrite existing key files.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the public key.
+    Export {
+        #[clap(long, arg_enum, default_value = "pem")]
+        format: ExportFormat,
+    },
+    /// Print the fingerprint of the public key, which is also its key id.
+    Fingerprint,
+}
+
+#[derive(Clone, ArgEnum)]
+enum KeyType {
+    Rsa,
+}
+
+#[derive(Clone, ArgEnum)]
+enum ExportFormat {
+    Pem,
+    Jwk,
+    Ssh,
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum DocumentFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// The format of a document, given or from the extension of its file, JSON otherwise.
+fn document_format(file: &Option<PathBuf>, format: Option<DocumentFormat>) -> Format {
+    let format = format.or_else(|| match file.as_ref()?.extension()?.to_str()? {
+        "yaml" | "yml" => Some(DocumentFormat::Yaml),
+        "toml" => Some(DocumentFormat::Toml),
+        _ => None,
+    });
+    match format {
+        Some(DocumentFormat::Yaml) => Format::Yaml,
+        Some(DocumentFormat::Toml) => Format::Toml,
+        Some(DocumentFormat::Json) | None => Format::Json,
+    }
+}
+
+/// Read a document in `format`, change it with `change`, and write it back in the same format, keeping what
+/// the format allows of the original (see `Format::encode_onto`).
+fn change_document(
+    file: &Option<PathBuf>,
+    format: Format,
+    public_key: &rsa::RsaPublicKey,
+    change: impl FnOnce(&Value) -> Result<Value, String>,
+) -> Result<Value, String> {
+    let original = read_input(file)?;
+    let changed = change(&format.decode(&original, public_key)?)?;
+    document_text(format.encode_onto(&original, &changed, public_key)?)
+}
+
+/// A document as text to print, without the final newline of YAML and TOML.
+fn document_text(document: Vec<u8>) -> Result<Value, String> {
+    let text = String::from_utf8(document).map_err(|e| e.to_string())?;
+    Ok(Value::String(text.trim_end_matches('\n').to_string()))
+}
+
+/// Read the whole file, or stdin if there is no file, as JSON.
+fn read_document(file: &Option<PathBuf>) -> Result<Value, String> {
+    let text = match file {
+        Some(path) => fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => {
+            let mut text = String::new();
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .map_err(|e| e.to_string())?;
+            text
+        }
+    };
+    serde_json::from_str(&text).map_err(|e| e.to_string())
+}
+
+/// Open a file, or standard input without one.
+fn open_input(file: &Option<PathBuf>) -> Result<Box<dyn Read>, String> {
+    Ok(match file {
+        Some(path) => {
+            Box::new(fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?)
+        }
+        None => Box::new(std::io::stdin()),
+    })
+}
+
+/// SHA256 of a file or standard input, read in chunks.
+fn hash_input(file: &Option<PathBuf>) -> Result<Vec<u8>, String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut open_input(file)?, &mut hasher).map_err(|e| e.to_string())?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn read_input(file: &Option<PathBuf>) -> Result<Vec<u8>, String> {
+    let mut content = vec![];
+    open_input(file)?
+        .read_to_end(&mut content)
+        .map_err(|e| e.to_string())?;
+    Ok(content)
+}
+
+/// Run a file or standard input through a blob stream into `output`, a chunk at a time. Returns the bytes
+/// written.
+fn run_blob(
+    file: &Option<PathBuf>,
+    output: &Path,
+    mut blob: impl BlobStream,
+) -> Result<usize, String> {
+    let mut input = open_input(file)?;
+    let mut out = fs::File::create(output).map_err(|e| format!("{}: {}", output.display(), e))?;
+    let mut written = 0;
+    let mut chunk = Zeroizing::new(vec![0; 64 * 1024]);
+    loop {
+        let read = input.read(&mut chunk).map_err(|e| e.to_string())?;
+        let data = if read == 0 {
+            blob.finish()?
+        } else {
+            blob.update(&chunk[..read])?
+        };
+        out.write_all(&data).map_err(|e| e.to_string())?;
+        written += data.len();
+        if read == 0 {
+            return Ok(written);
+        }
+    }
+}
+
+fn write_output(output: &Path, data: &[u8]) -> Result<usize, String> {
+    fs::write(output, data).map_err(|e| format!("{}: {}", output.display(), e))?;
+    Ok(data.len())
+}
+
+fn read_keys(args: &Args) -> Result<(rsa::RsaPublicKey, rsa::RsaPrivateKey), String> {
+    crypto::read_keys(&args.public_key, &args.private_key).ok_or_else(|| {
+        format!(
+            "could not read keys from {} and {}",
+            args.public_key.display(),
+            args.private_key.display()
+        )
+    })
+}
+
+fn read_key(args: &Args) -> Result<SoftwareKey, String> {
+    Ok(SoftwareKey::new(read_keys(args)?.1))
+}
+
+/// Fail if writing the key files would replace existing keys, unless forced.
+fn check_overwrite(args: &Args, force: bool) -> Result<(), String> {
+    match [&args.public_key, &args.private_key]
+        .iter()
+        .find(|path| path.exists())
+    {
+        Some(path) if !force => Err(format!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Run a key management command. Returns the text to print.
+fn run_keys(args: &Args, command: &KeysCommand) -> Result<String, String> {
+    match command {
+        KeysCommand::Generate {
+            key_type: KeyType::Rsa,
+            bits,
+            force,
+        } => {
+            if *bits < keys::MIN_BITS {
+                return Err(format!("keys must be at least {} bits", keys::MIN_BITS));
+            }
+            check_overwrite(args, *force)?;
+            let (public_key, private_key) = crypto::generate_keys(&mut OsRng, *bits);
+            crypto::write_keys(
+                &args.public_key,
+                &args.private_key,
+                &public_key,
+                &private_key,
+            )?;
+            Ok(field_crypto::fingerprint(&public_key))
+        }
+        KeysCommand::Import { file, force } => {
+            let data = Zeroizing::new(match file {
+                Some(path) => fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?,
+                None => {
+                    let mut data = vec![];
+                    std::io::stdin()
+                        .read_to_end(&mut data)
+                        .map_err(|e| e.to_string())?;
+                    data
+                }
+            });
+            let private_key = keys::import_private_key(&data)?;
+            check_overwrite(args, *force)?;
+            let public_key = rsa::RsaPublicKey::from(&private_key);
+            crypto::write_keys(
+                &args.public_key,
+                &args.private_key,
+                &public_key,
+                &private_key,
+            )?;
+            Ok(field_crypto::fingerprint(&public_key))
+        }
+        KeysCommand::Export { format } => {
+            let public_key = field_crypto::load_public_key(&args.public_key)?;
+            Ok(match format {
+                ExportFormat::Pem => keys::export_pem(&public_key).trim_end().to_string(),
+                ExportFormat::Jwk => keys::export_jwk(&public_key),
+                ExportFormat::Ssh => keys::export_ssh(&public_key),
+            })
+        }
+        KeysCommand::Fingerprint => Ok(field_crypto::fingerprint(&field_crypto::load_public_key(
+            &args.public_key,
+        )?)),
+    }
+}
+
+/// Run the command locally. Returns the document to print and whether the command succeeded.
+fn run_local(args: &Args) -> Result<(Value, bool), String> {
+    match &args.command {
+        Command::Encrypt {
+            fields,
+            format,
+            file,
+        } => {
+            let public_key = field_crypto::load_public_key(&args.public_key)?;
+            let encrypted = change_document(
+                file,
+                document_format(file, *format),
+                &public_key,
+                |document| crypto::detect_and_encrypt(document, &public_key, fields),
+            )?;
+            Ok((encrypted, true))
+        }
+        Command::Decrypt { format, file } => {
+            let key = read_key(args)?;
+            let decrypted = change_document(
+                file,
+                document_format(file, *format),
+                key.public_key(),
+                |document| Ok(crypto::detect_and_decrypt(document, &key)),
+            )?;
+            Ok((decrypted, true))
+        }
+        Command::Sign {
+            per_field: true,
+            file,
+            ..
+        } => Ok((
+            json!({
+                "signatures": crypto::get_field_signatures(&read_document(file)?, &read_key(args)?)?
+            }),
+            true,
+        )),
+        Command::Sign {
+            envelope: true,
+            ttl,
+            audience,
+            file,
+            ..
+        } => {
+            let envelope = Envelope::new(
+                ttl.unwrap_or(EnvelopeSettings::default().default_ttl),
+                audience.clone(),
+            );
+            let signature =
+                crypto::get_envelope_signature(&read_document(file)?, &envelope, &read_key(args)?)?;
+            Ok((
+                json!({ "signature": signature, "envelope": envelope }),
+                true,
+            ))
+        }
+        Command::Sign { file, .. } => Ok((
+            json!({ "signature": crypto::get_signature(&read_document(file)?, &read_key(args)?)? }),
+            true,
+        )),
+        Command::Verify { audience, file } => {
+            let document = read_document(file)?;
+            if document.get("signatures").is_some() {
+                let verification = crypto::get_field_verification(
+                    &document,
+                    &read_key(args)?,
+                    &|_| true,
+                    usize::MAX,
+                )?;
+                let valid = verification.failed.is_empty();
+                return Ok((
+                    json!({
+                        "verified": verification.verified,
+                        "failed": verification.failed,
+                        "unsigned": verification.unsigned,
+                    }),
+                    valid,
+                ));
+            }
+            // nonces are only remembered by a server
+            let valid = crypto::get_verification(
+                &document,
+                &read_key(args)?,
+                &|_| true,
+                usize::MAX,
+                &NonceStore::new(1),
+                audience.as_deref(),
+            )
+            .map_err(|e| e.to_string())?;
+            Ok((json!({ "valid": valid }), valid))
+        }
+        Command::SignRaw { file } => Ok((
+            json!({ "signature": crypto::sign_digest(&hash_input(file)?, &read_key(args)?)? }),
+            true,
+        )),
+        Command::VerifyRaw { signature, file } => {
+            let valid = crypto::verify_digest(&hash_input(file)?, signature, &read_key(args)?)?;
+            Ok((json!({ "valid": valid }), valid))
+        }
+        Command::EncryptBlob { output, file } => {
+            let encryptor = BlobEncryptor::new(&field_crypto::load_public_key(&args.public_key)?)?;
+            Ok((json!({ "bytes": run_blob(file, output, encryptor)? }), true))
+        }
+        Command::DecryptBlob { output, file } => {
+            let key = read_key(args)?;
+            let decryptor =
+                BlobDecryptor::new(|wrapped: &[u8]| crypto::unwrap_raw_key(&key, wrapped));
+            Ok((json!({ "bytes": run_blob(file, output, decryptor)? }), true))
+        }
+        Command::Keygen => {
+            let (public_key, _) =
+                crypto::load_keys(&args.public_key, &args.private_key, &mut OsRng);
+            Ok((json!({ "bits": public_key.size() * 8 }), true))
+        }
+        Command::Inspect { file } => {
+            let public_key = field_crypto::load_public_key(&args.public_key)?;
+            Ok((
+                json!({
+                    "encryptedPaths": field_crypto::ciphertext_paths(&read_document(file)?, &public_key)
+                }),
+                true,
+            ))
+        }
+        Command::VerifyAudit { file } => Ok(match audit::verify_chain(file) {
+            Ok(entries) => (json!({ "valid": true, "entries": entries }), true),
+            Err(e) => (json!({ "valid": false, "error": e }), false),
+        }),
+        Command::Keys { command } => Ok((Value::String(run_keys(args, command)?), true)),
+        #[cfg(unix)]
+        Command::Agent { socket } => {
+            provider::agent::serve(std::sync::Arc::new(read_key(args)?), socket)?;
+            Ok((Value::Null, true))
+        }
+        #[cfg(not(unix))]
+        Command::Agent { .. } => Err("signing agents need Unix sockets".to_string()),
+    }
+}
+
+/// Run the command against a server. Returns the document to print and whether the command succeeded.
+async fn run_remote(args: &Args, server: &str) -> Result<(Value, bool), String> {
+    let mut builder = client::Client::builder(server);
+    if let Some(api_key) = &args.api_key {
+        builder = builder.api_key(api_key);
+    }
+    if let (Some(client_id), Some(secret)) = (&args.client_id, &args.hmac_secret) {
+        builder = builder.hmac(client_id, secret);
+    }
+    if let Some(file) = &args.ca_certificate {
+        builder = builder
+            .root_certificate(&fs::read(file).map_err(|e| format!("{}: {}", file.display(), e))?);
+    }
+    if let Some(file) = &args.identity {
+        builder = builder.identity(
+            &fs::read(file).map_err(|e| format!("{}: {}", file.display(), e))?,
+            &args.identity_password,
+        );
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+    match &args.command {
+        Command::Encrypt { fields, .. } if !fields.is_empty() => {
+            Err("--field only applies locally, the server uses its own configuration".to_string())
+        }
+        Command::Encrypt { format, file, .. } => {
+            let encrypted = client
+                .encrypt_as(document_format(file, *format), &read_input(file)?)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((document_text(encrypted)?, true))
+        }
+        Command::Decrypt { format, file } => {
+            let decrypted = client
+                .decrypt_as(document_format(file, *format), &read_input(file)?)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((document_text(decrypted)?, true))
+        }
+        Command::Sign {
+            per_field: true,
+            file,
+            ..
+        } => Ok((
+            json!({
+                "signatures": client
+                    .sign_fields(&read_document(file)?)
+                    .await
+                    .map_err(|e| e.to_string())?
+            }),
+            true,
+        )),
+        Command::Sign {
+            envelope: true,
+            ttl,
+            audience,
+            file,
+            ..
+        } => Ok((
+            client
+                .sign_envelope(&read_document(file)?, *ttl, audience.as_deref())
+                .await
+                .map_err(|e| e.to_string())?,
+            true,
+        )),
+        Command::Sign { file, .. } => Ok((
+            json!({
+                "signature": client.sign(&read_document(file)?).await.map_err(|e| e.to_string())?
+            }),
+            true,
+        )),
+        Command::Verify { audience, file } => {
+            let document = read_document(file)?;
+            if let Some(signatures) = document.get("signatures") {
+                let report = client
+                    .verify_fields(document.get("data").ok_or("missing payload")?, signatures)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let valid = report["failed"].as_array().is_some_and(Vec::is_empty);
+                return Ok((report, valid));
+            }
+            if document.get("envelope").is_some() {
+                let valid = client
+                    .verify_envelope(
+                        document.get("data").ok_or("missing payload")?,
+                        &document,
+                        audience.as_deref(),
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?;
+                return Ok((json!({ "valid": valid }), valid));
+            }
+            let signature = document
+                .get("signature")
+                .ok_or("missing signature")?
+                .as_str()
+                .ok_or("signature must be a string")?;
+            let valid = client
+                .verify(document.get("data").ok_or("missing payload")?, signature)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((json!({ "valid": valid }), valid))
+        }
+        Command::SignRaw { file } => Ok((
+            json!({
+                "signature": client.sign_raw(&read_input(file)?).await.map_err(|e| e.to_string())?
+            }),
+            true,
+        )),
+        Command::VerifyRaw { signature, file } => {
+            let valid = client
+                .verify_raw(&read_input(file)?, signature)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((json!({ "valid": valid }), valid))
+        }
+        Command::EncryptBlob { output, file } => {
+            let blob = client
+                .encrypt_blob(&read_input(file)?)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((json!({ "bytes": write_output(output, &blob)? }), true))
+        }
+        Command::DecryptBlob { output, file } => {
+            let content = client
+                .decrypt_blob(&read_input(file)?)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok((json!({ "bytes": write_output(output, &content)? }), true))
+        }
+        Command::Keygen | Command::Inspect { .. } | Command::Keys { .. } => {
+            Err("keygen, inspect and keys only operate on local key files".to_string())
+        }
+        Command::VerifyAudit { .. } => Err("verify-audit only reads a local log file".to_string()),
+        Command::Agent { .. } => Err("agent serves the local key files".to_string()),
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let result = match &args.server {
+        Some(server) => tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start runtime")
+            .block_on(run_remote(&args, server)),
+        None => run_local(&args),
+    };
+    match result {
+        Ok((output, success)) => {
+            match output {
+                // plain text output such as PEM keys
+                Value::String(text) => println!("{}", text),
+                output => println!("{}", output),
+            }
+            if !success {
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(2);
+        }
+    }
+}
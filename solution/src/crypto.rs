@@ -1,19 +1,90 @@
-use crypto_hash::{digest, Algorithm};
+use crate::envelope::{Envelope, NonceStore, VerifyError};
+use crate::provider::KeyProvider;
+use crate::vault::TokenVault;
+use field_crypto::actions::{detect_and_apply, text, FieldAction};
+use field_crypto::{decode_ciphertext, looks_encrypted, pointer_push, signing_digest};
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
+use rand::RngCore;
 use rsa::pkcs8::{FromPrivateKey, FromPublicKey, ToPrivateKey, ToPublicKey};
-use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use rsa::{PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 
-/// Generate a new RSA 2048 bitkey pair.
-fn generate_keys(rng: &mut OsRng) -> (RsaPublicKey, RsaPrivateKey) {
-    let bits = 2048;
+pub use field_crypto::detect_and_encrypt;
+
+/// Generate a new RSA key pair of the given size in bits.
+pub fn generate_keys(rng: &mut OsRng, bits: usize) -> (RsaPublicKey, RsaPrivateKey) {
     let priv_key = RsaPrivateKey::new(rng, bits).expect("failed to generate a key");
     let pub_key = RsaPublicKey::from(&priv_key);
     (pub_key, priv_key)
 }
 
+/// Write a key pair to the public (SPKI PEM) and private (PKCS#8 PEM) key files, in the format read by `read_keys`.
+/// The private key is written first, so a failure never leaves a public key without its private key.
+pub fn write_keys<T: AsRef<Path>, S: AsRef<Path>>(
+    public_file: T,
+    private_file: S,
+    public: &RsaPublicKey,
+    private: &RsaPrivateKey,
+) -> Result<(), String> {
+    let private_pem = private
+        .to_pkcs8_pem()
+        .map_err(|_| "failed to serialize private key")?;
+    write_private_file(private_file.as_ref(), private_pem.as_bytes())
+        .map_err(|_| "failed to write private key")?;
+    let public_pem = public
+        .to_public_key_pem()
+        .map_err(|_| "failed to serialize public key")?;
+    fs::write(public_file, public_pem).map_err(|_| "failed to write public key")?;
+    Ok(())
+}
+
+/// Write `data` to `file`, readable by its owner only, through a new temporary file renamed over it, so the
+/// file is never readable by others or only partly written.
+fn write_private_file(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temporary = file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    // left by a write that was interrupted
+    let _ = fs::remove_file(&temporary);
+    let mut options = fs::OpenOptions::new();
+    // never through a file or link someone else put there meanwhile
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut temporary_file = options.open(&temporary)?;
+    let written = temporary_file
+        .write_all(data)
+        .and_then(|_| temporary_file.sync_all())
+        .and_then(|_| fs::rename(&temporary, file));
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// Takes paths to the private and public key files. Returns the key pair if both files exist and contain valid keys.
+pub fn read_keys<T: AsRef<Path>, S: AsRef<Path>>(
+    public_file: T,
+    private_file: S,
+) -> Option<(RsaPublicKey, RsaPrivateKey)> {
+    let public = fs::read_to_string(public_file)
+        .ok()
+        .and_then(|data| RsaPublicKey::from_public_key_pem(&data).ok())?;
+    let private = fs::read_to_string(private_file)
+        .ok()
+        .map(Zeroizing::new)
+        .and_then(|data| RsaPrivateKey::from_pkcs8_pem(&data).ok())?;
+    Some((public, private))
+}
+
 /// Takes paths to the private and public key files. If they do not exist and contain valid key pairs, then a key pair is generated with `generate_keys`
 /// and the keys are written to the files. Returns either the loaded or generated key pair.
 pub fn load_keys<T: AsRef<Path> + Copy, S: AsRef<Path> + Copy>(
@@ -21,163 +92,643 @@ pub fn load_keys<T: AsRef<Path> + Copy, S: AsRef<Path> + Copy>(
     private_file: S,
     rng: &mut OsRng,
 ) -> (RsaPublicKey, RsaPrivateKey) {
-    if let (Some(public), Some(private)) = (
-        fs::read_to_string(public_file)
-            .as_ref()
-            .map(|data| RsaPublicKey::from_public_key_pem(data).ok())
-            .ok()
-            .flatten(),
-        fs::read_to_string(private_file)
-            .as_ref()
-            .map(|data| RsaPrivateKey::from_pkcs8_pem(data).ok())
-            .ok()
-            .flatten(),
-    ) {
-        (public, private)
+    if let Some(keys) = read_keys(public_file, private_file) {
+        keys
     } else {
-        let (public, private) = generate_keys(rng);
-        fs::write(
-            public_file,
-            public
-                .to_public_key_pem()
-                .expect("failed to serialize public key"),
-        )
-        .expect("failed to write public key");
-        let private_pem = private
-            .to_pkcs8_pem()
-            .expect("failed to serialize private key");
-        fs::write(private_file, private_pem.as_bytes()).expect("failed to write private key");
+        let (public, private) = generate_keys(rng, 2048);
+        write_keys(public_file, private_file, &public, &private).expect("failed to write keys");
         (public, private)
     }
 }
 
-/// Encrypt a slice of bytes using the public key.
-fn encrypt_pub_slice(pub_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>, String> {
-    Ok(pub_key
-        .encrypt(&mut OsRng, PaddingScheme::new_pkcs1v15_encrypt(), data)
-        .map_err(|_| "failed to encrypt")?)
-}
+/// The only error of a failed decryption. Which step failed is not told apart, neither in the error nor in
+/// the time taken, so that a caller cannot use the server as a padding oracle.
+const DECRYPTION_FAILED: &str = "decryption failed";
 
-/// Encrypt and then base64 encode a string using the public key.
-fn encrypt_pub_string(pub_key: &RsaPublicKey, data: &str) -> Result<String, String> {
-    Ok(base64::encode(encrypt_pub_slice(pub_key, data.as_bytes())?))
+/// Decrypt a base64 encoded string using the private key, returning the plaintext bytes, which are wiped
+/// from memory when dropped. A string that is not the base64 of one RSA block still costs a decryption, of a
+/// dummy block, so it fails as slowly as a bad padding.
+fn decrypt_private_string(
+    key: &dyn KeyProvider,
+    data: &str,
+) -> Result<Zeroizing<Vec<u8>>, &'static str> {
+    decrypt_private_slice(key, &decode_ciphertext(data).unwrap_or_default())
 }
 
-/// Decrypt a slice of bytes using the private key.
+/// `decrypt_private_string` for a raw block.
 fn decrypt_private_slice(
-    priv_key: &RsaPrivateKey,
-    data: &[u8],
-) -> Result<Vec<u8>, rsa::errors::Error> {
-    priv_key.decrypt(PaddingScheme::new_pkcs1v15_encrypt(), data)
+    key: &dyn KeyProvider,
+    block: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, &'static str> {
+    let size = key.public_key().size();
+    let block = Some(block).filter(|block| block.len() == size);
+    // any block below the modulus, whose first byte is never zero, works
+    let mut dummy = vec![0xff; size];
+    dummy[0] = 0;
+    let decrypted = key.decrypt(block.unwrap_or(&dummy));
+    match (block, decrypted) {
+        (Some(_), Ok(plaintext)) => Ok(plaintext),
+        _ => Err(DECRYPTION_FAILED),
+    }
 }
 
-/// Decrypt a base64 encoded string using the private key.
-fn decrypt_private_string(priv_key: &RsaPrivateKey, data: &str) -> Result<String, String> {
-    let data = base64::decode(data).map_err(|_| "failed to decode base64")?;
-    Ok(
-        String::from_utf8(decrypt_private_slice(priv_key, &data).map_err(|_| "failed to decrypt")?)
-            .map_err(|_| "invalid utf8 string")?,
-    )
+/// Wrap a data key with the public key, base64 encoded like encrypted fields, in the format of
+/// `field_crypto::wrap_data_key`. Keys up to `field_crypto::max_data_key_size` bytes (232 for a 2048 bit key)
+/// fit.
+pub fn wrap_key(key: &dyn KeyProvider, plaintext: &[u8]) -> Result<String, String> {
+    Ok(field_crypto::encode_ciphertext(
+        &field_crypto::wrap_data_key(key.public_key(), plaintext)?,
+    ))
 }
 
-/// Takes a serde_json::Value and encrypts it using the public key on every key specified by the Config (/config endpoint).
-pub fn detect_and_encrypt(
-    payload: &Value,
-    public_key: &RsaPublicKey,
-    fields: &[String],
+/// Unwrap a data key made by `wrap_key`. Fails the same way whatever is wrong with the ciphertext, including
+/// being an encrypted field.
+pub fn unwrap_key(key: &dyn KeyProvider, ciphertext: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    unwrap_raw_key(key, &decode_ciphertext(ciphertext).unwrap_or_default())
+}
+
+/// `unwrap_key` for a wrapped key that is not base64 encoded, as in the header of a `field_crypto::blob`.
+pub fn unwrap_raw_key(key: &dyn KeyProvider, wrapped: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    let block = decrypt_private_slice(key, wrapped).map_err(str::to_string)?;
+    field_crypto::unwrapped_data_key(&block)
+        .map(|data_key| Zeroizing::new(data_key.to_vec()))
+        .ok_or_else(|| DECRYPTION_FAILED.to_string())
+}
+
+/// A new random data key of `bytes` bytes.
+pub fn generate_data_key(bytes: usize) -> Zeroizing<Vec<u8>> {
+    let mut data_key = Zeroizing::new(vec![0; bytes]);
+    OsRng.fill_bytes(&mut data_key);
+    data_key
+}
+
+/// Recursively traverses a serde_json::Value and decrypts all strings using the private key.
+pub fn detect_and_decrypt(data: &Value, key: &dyn KeyProvider) -> Value {
+    detect_and_decrypt_fields(data, key, &|_| true, usize::MAX).expect("no limit on decryptions")
+}
+
+/// Like `detect_and_decrypt`, but only decrypts the strings whose JSON Pointer is accepted by `allowed`.
+/// Other encrypted strings are left as they are, as are those encrypted at another JSON Pointer, and those
+/// encrypted before fields were bound to their JSON Pointers unless `allowed` accepts the whole document, `""`. Fails without decrypting anything if more than
+/// `max_attempts` strings would be tried.
+pub fn detect_and_decrypt_fields(
+    data: &Value,
+    key: &dyn KeyProvider,
+    allowed: &dyn Fn(&str) -> bool,
+    max_attempts: usize,
 ) -> Result<Value, String> {
-    let mut data = payload.clone();
-    match &mut data {
-        Value::Array(vec) => {
-            for entry in vec.iter_mut() {
-                *entry = detect_and_encrypt(entry, public_key, fields)?;
-            }
-        }
-        Value::Object(map) => {
-            for (key, value) in map.iter_mut() {
-                if fields.contains(&key.to_string()) {
-                    *value = json!(encrypt_pub_string(public_key, &value.to_string())?);
-                } else {
-                    // the value is not to be encrypted, if the value is an array or object, recurse
-                    *value = detect_and_encrypt(value, public_key, fields)?;
+    let attempts = decryption_attempts(data, allowed);
+    if attempts > max_attempts {
+        return Err(format!(
+            "too many fields to decrypt: {} (at most {})",
+            attempts, max_attempts
+        ));
+    }
+    Ok(decrypt_at(data, key, "", allowed))
+}
+
+/// Call `visit` with the JSON Pointer of every string `detect_and_decrypt` considers: values of object keys
+/// at any depth, also in objects inside arrays.
+fn visit_strings(data: &mut Value, path: &str, visit: &mut dyn FnMut(&str, &mut Value)) {
+    if let Some(map) = data.as_object_mut() {
+        for (key, entry) in map.iter_mut() {
+            let path = pointer_push(path, key);
+            match entry {
+                Value::String(_) => visit(&path, entry),
+                Value::Array(array) => {
+                    for (index, entry) in array.iter_mut().enumerate() {
+                        visit_strings(entry, &pointer_push(&path, &index.to_string()), visit);
+                    }
                 }
+                Value::Object(_) => visit_strings(entry, &path, visit),
+                _ => {}
             }
         }
-        _ => {}
     }
-    Ok(data)
 }
 
-/// Recursively traverses a serde_json::Value and decrypts all strings using the private key.
-pub fn detect_and_decrypt(data: &Value, private_key: &RsaPrivateKey) -> Value {
+/// The number of strings `decrypt_at` tries to decrypt.
+fn decryption_attempts(data: &Value, allowed: &dyn Fn(&str) -> bool) -> usize {
+    let mut attempts = 0;
+    visit_strings(&mut data.clone(), "", &mut |path, _| {
+        attempts += allowed(path) as usize
+    });
+    attempts
+}
+
+/// Decrypt an encrypted field back into its JSON value, with the JSON Pointer it is bound to, see
+/// `field_crypto::decode_field`.
+pub fn decrypt_field(key: &dyn KeyProvider, string: &str) -> Option<(Option<String>, Value)> {
+    // every failure, including a plaintext that is not utf8 JSON, is the same
+    decrypt_private_string(key, string)
+        .ok()
+        .and_then(|plaintext| field_crypto::decode_field(&plaintext))
+}
+
+/// Decrypt a value encrypted without a path, like the entries of a token vault.
+pub fn decrypt_value(key: &dyn KeyProvider, string: &str) -> Option<Value> {
+    match decrypt_field(key, string)? {
+        (None, value) => Some(value),
+        (Some(_), _) => None,
+    }
+}
+
+fn decrypt_at(
+    data: &Value,
+    private_key: &dyn KeyProvider,
+    path: &str,
+    allowed: &dyn Fn(&str) -> bool,
+) -> Value {
     let mut data = data.clone();
-    if let Some(map) = data.as_object_mut() {
-        for entry in map.values_mut() {
-            if let Value::String(string) = entry {
-                // Reasonable assumption that the encrypted string will not contain encrypted fields after decryption.
-                // (not encrypted twice)
-                if let Some(valid_decrypted) = decrypt_private_string(private_key, string)
-                    .ok()
-                    .and_then(|s| serde_json::from_str(&s).ok())
-                {
-                    *entry = valid_decrypted;
+    visit_strings(&mut data, path, &mut |path, entry| {
+        // Reasonable assumption that the encrypted string will not contain encrypted fields after decryption.
+        // (not encrypted twice)
+        if !allowed(path) {
+            return;
+        }
+        match entry
+            .as_str()
+            .and_then(|string| decrypt_field(private_key, string))
+        {
+            Some((Some(bound), value)) if bound == path => *entry = value,
+            // encrypted before fields were bound, it may have been moved here from any field
+            Some((None, value)) if allowed("") => *entry = value,
+            _ => {}
+        }
+    });
+    data
+}
+
+/// A field re-encrypted by `reencrypt`.
+pub struct Migrated {
+    pub path: String,
+    /// Id of the key it was encrypted with.
+    pub from_key_id: String,
+}
+
+/// Re-encrypt with `current` the fields encrypted with one of the `retired` keys, trying each key in turn.
+/// Returns the document, the migrated fields, and the fields that look encrypted but that no key decrypts.
+/// Fails without decrypting anything if more than `max_attempts` strings would be tried.
+pub fn reencrypt(
+    data: &Value,
+    current: &dyn KeyProvider,
+    retired: &[&dyn KeyProvider],
+    max_attempts: usize,
+) -> Result<(Value, Vec<Migrated>, Vec<String>), String> {
+    let attempts = decryption_attempts(data, &|_| true);
+    if attempts > max_attempts {
+        return Err(format!(
+            "too many fields to re-encrypt: {} (at most {})",
+            attempts, max_attempts
+        ));
+    }
+    let mut data = data.clone();
+    let mut migrated = vec![];
+    let mut failed = vec![];
+    let mut error = None;
+    visit_strings(&mut data, "", &mut |path, entry| {
+        let string = entry.as_str().unwrap_or_default().to_string();
+        if decrypt_field(current, &string).is_some() {
+            return;
+        }
+        for old in retired {
+            // the plaintext is encrypted again as it is, still bound to the same JSON Pointer if it was
+            let plaintext = match decrypt_private_string(*old, &string) {
+                // a wrong key may still find valid padding, but then hardly a field
+                Ok(plaintext) if field_crypto::decode_field(&plaintext).is_some() => plaintext,
+                _ => continue,
+            };
+            match field_crypto::encrypt_pub_slice(current.public_key(), &plaintext) {
+                Ok(ciphertext) => {
+                    *entry = Value::String(field_crypto::encode_ciphertext(&ciphertext));
+                    migrated.push(Migrated {
+                        path: path.to_string(),
+                        from_key_id: old.key_id(),
+                    });
                 }
-            } else if let Value::Array(array) = entry {
-                for entry in array.iter_mut() {
-                    *entry = detect_and_decrypt(entry, private_key);
+                Err(_) => {
+                    error.get_or_insert(format!("{} is too large for the current key", path));
                 }
-            } else if let Value::Object(_object) = entry {
-                *entry = detect_and_decrypt(entry, private_key);
             }
+            return;
+        }
+        if std::iter::once(current)
+            .chain(retired.iter().copied())
+            .any(|key| field_crypto::looks_encrypted(&string, key.public_key()))
+        {
+            failed.push(path.to_string());
         }
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok((data, migrated, failed)),
     }
-    data
 }
 
 /// Get a signature for a serde_json::Value using the private key.
 /// Hashes the value using SHA256 and then signs the hash using the private key.
-pub fn get_signature(payload: &Value, private_key: &RsaPrivateKey) -> String {
-    base64::encode(
-        private_key
-            .sign(
-                PaddingScheme::new_pkcs1v15_sign(None),
-                &digest(Algorithm::SHA256, payload.to_string().as_bytes()),
-            )
-            // padding scheme guaranteed to be valid
-            .unwrap(),
-    )
+pub fn get_signature(payload: &Value, key: &dyn KeyProvider) -> Result<String, String> {
+    sign_digest(&signing_digest(payload), key)
+}
+
+/// The base64 signature of a SHA256 digest, the same format as `get_signature` for content that is not JSON.
+pub fn sign_digest(digest: &[u8], key: &dyn KeyProvider) -> Result<String, String> {
+    Ok(base64::encode(key.sign(digest)?))
+}
+
+/// Whether a base64 signature from `sign_digest` matches a SHA256 digest.
+pub fn verify_digest(
+    digest: &[u8],
+    signature: &str,
+    key: &dyn KeyProvider,
+) -> Result<bool, String> {
+    let signature = base64::decode(signature).map_err(|_| "failed to decode signature")?;
+    Ok(key
+        .public_key()
+        .verify(PaddingScheme::new_pkcs1v15_sign(None), digest, &signature)
+        .is_ok())
+}
+
+/// Whether a base64 signature matches a plaintext value.
+fn signature_matches(
+    plaintext: &Value,
+    signature: &str,
+    key: &dyn KeyProvider,
+) -> Result<bool, String> {
+    let signature = base64::decode(signature).map_err(|_| "failed to decode signature")?;
+    Ok(key
+        .public_key()
+        .verify(
+            PaddingScheme::new_pkcs1v15_sign(None),
+            &signing_digest(plaintext),
+            &signature,
+        )
+        .is_ok())
+}
+
+/// Sign a plaintext document in an envelope, see `envelope::Envelope`.
+pub fn get_envelope_signature(
+    payload: &Value,
+    envelope: &Envelope,
+    key: &dyn KeyProvider,
+) -> Result<String, String> {
+    get_signature(&json!({ "data": payload, "envelope": envelope }), key)
 }
 
 /// Verify a signature for a serde_json::Value using the public key. Decrypts using the private key any encrypted fields.
-/// Requires a signature and a data object.
+/// Requires a signature and a data object. At most `max_decryptions` fields are decrypted, and only those `allowed`
+/// accepts, as with `get_field_verification`.
+/// With an `envelope`, a matching signature is still refused when the envelope expired, its nonce is already in
+/// `nonces`, or it is for another audience than `audience`; otherwise its nonce is added to `nonces`.
 pub fn get_verification(
     payload: &Value,
-    public_key: &RsaPublicKey,
-    private_key: &RsaPrivateKey,
-) -> Result<bool, String> {
+    key: &dyn KeyProvider,
+    allowed: &dyn Fn(&str) -> bool,
+    max_decryptions: usize,
+    nonces: &NonceStore,
+    audience: Option<&str>,
+) -> Result<bool, VerifyError> {
     let signature = payload
         .get("signature")
         .ok_or("missing signature")?
         .as_str()
         .ok_or("signature must be a string")?;
-    let signature = base64::decode(signature).map_err(|_| "failed to decode signature")?;
-    Ok(public_key
-        .verify(
-            PaddingScheme::new_pkcs1v15_sign(None),
-            &digest(
-                Algorithm::SHA256,
-                detect_and_decrypt(payload.get("data").ok_or("missing payload")?, private_key)
-                    .to_string()
-                    .as_bytes(),
+    let envelope = payload
+        .get("envelope")
+        .map(Envelope::deserialize)
+        .transpose()
+        .map_err(|e| format!("invalid envelope: {}", e))?;
+    let data = payload.get("data").ok_or("missing payload")?;
+    check_decryptable(data, key.public_key(), allowed)?;
+    let plaintext = detect_and_decrypt_fields(data, key, allowed, max_decryptions)?;
+    let signed = match &envelope {
+        Some(envelope) => json!({ "data": plaintext, "envelope": envelope }),
+        None => plaintext,
+    };
+    if !signature_matches(&signed, signature, key)? {
+        return Ok(false);
+    }
+    if let Some(envelope) = &envelope {
+        nonces.accept(envelope, audience)?;
+    }
+    Ok(true)
+}
+
+/// Refuse a document with ciphertexts at JSON Pointers `allowed` rejects, as checking a signature over their
+/// plaintext would tell whether a guess of it is right.
+fn check_decryptable(
+    data: &Value,
+    public_key: &RsaPublicKey,
+    allowed: &dyn Fn(&str) -> bool,
+) -> Result<(), String> {
+    let mut refused = vec![];
+    visit_strings(&mut data.clone(), "", &mut |path, value| {
+        if !allowed(path)
+            && value
+                .as_str()
+                .is_some_and(|s| looks_encrypted(s, public_key))
+        {
+            refused.push(path.to_string());
+        }
+    });
+    if refused.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "not allowed to decrypt {} to verify",
+        refused.join(", ")
+    ))
+}
+
+/// JSON Pointers and values of the fields of a document: the values of object keys that are not objects
+/// themselves, at any depth and also in objects inside arrays. Arrays of other values are one field.
+fn fields(data: &Value) -> Vec<(String, &Value)> {
+    fn has_fields(value: &Value) -> bool {
+        match value {
+            Value::Object(map) => !map.is_empty(),
+            Value::Array(array) => array.iter().any(has_fields),
+            _ => false,
+        }
+    }
+    fn walk<'a>(data: &'a Value, path: &str, found: &mut Vec<(String, &'a Value)>) {
+        let entries: Box<dyn Iterator<Item = (String, &Value)>> = match data {
+            Value::Object(map) => Box::new(map.iter().map(|(key, entry)| (key.clone(), entry))),
+            Value::Array(array) => Box::new(
+                array
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| (index.to_string(), entry)),
             ),
-            &signature,
-        )
-        .is_ok())
+            _ => return,
+        };
+        for (key, entry) in entries {
+            let path = pointer_push(path, &key);
+            if has_fields(entry) {
+                walk(entry, &path, found);
+            } else {
+                found.push((path, entry));
+            }
+        }
+    }
+    let mut found = vec![];
+    walk(data, "", &mut found);
+    found
+}
+
+/// What a field signature is computed over: the field's JSON Pointer with its plaintext value, so a signature
+/// cannot be moved to another field.
+fn field_signing_payload(path: &str, value: &Value) -> Value {
+    json!({ "path": path, "value": value })
+}
+
+/// Sign every field of a plaintext document on its own, so redacting or re-encrypting one field leaves the
+/// others verifiable. Returns the signatures by JSON Pointer.
+pub fn get_field_signatures(
+    payload: &Value,
+    key: &dyn KeyProvider,
+) -> Result<BTreeMap<String, String>, String> {
+    fields(payload)
+        .into_iter()
+        .map(|(path, value)| {
+            let signature = get_signature(&field_signing_payload(&path, value), key)?;
+            Ok((path, signature))
+        })
+        .collect()
+}
+
+/// Outcome of `get_field_verification`, as JSON Pointers.
+pub struct FieldVerification {
+    pub verified: Vec<String>,
+    /// Fields whose signature does not match, or that are missing from the document.
+    pub failed: Vec<String>,
+    /// Fields of the document without a signature.
+    pub unsigned: Vec<String>,
+}
+
+/// Verify the signatures of `get_field_signatures` field by field. Requires a `signatures` object and a data
+/// object, whose encrypted fields are decrypted first. At most `max_decryptions` fields are decrypted, and
+/// only at the JSON Pointers `allowed` accepts, as `check_decryptable` refuses the others.
+pub fn get_field_verification(
+    payload: &Value,
+    key: &dyn KeyProvider,
+    allowed: &dyn Fn(&str) -> bool,
+    max_decryptions: usize,
+) -> Result<FieldVerification, String> {
+    let signatures = payload
+        .get("signatures")
+        .ok_or("missing signatures")?
+        .as_object()
+        .ok_or("signatures must be an object")?;
+    let data = payload.get("data").ok_or("missing payload")?;
+    check_decryptable(data, key.public_key(), allowed)?;
+    let plaintext = detect_and_decrypt_fields(data, key, allowed, max_decryptions)?;
+    let mut verification = FieldVerification {
+        verified: vec![],
+        failed: vec![],
+        unsigned: vec![],
+    };
+    for (path, signature) in signatures {
+        let signature = signature
+            .as_str()
+            .ok_or_else(|| format!("signature of {} must be a string", path))?;
+        let matches = match plaintext.pointer(path) {
+            Some(value) => signature_matches(&field_signing_payload(path, value), signature, key)?,
+            None => false,
+        };
+        if matches {
+            verification.verified.push(path.clone());
+        } else {
+            verification.failed.push(path.clone());
+        }
+    }
+    verification.unsigned = fields(&plaintext)
+        .into_iter()
+        .map(|(path, _)| path)
+        .filter(|path| !signatures.contains_key(path))
+        .collect();
+    Ok(verification)
+}
+
+/// Encrypt the values at the JSON Pointers `paths`, in order. A path inside a value encrypted before it, or
+/// that is not in `payload`, is skipped.
+pub fn encrypt_paths(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    paths: &[String],
+) -> Result<Value, String> {
+    let mut data = payload.clone();
+    for path in paths {
+        if let Some(value) = data.pointer_mut(path) {
+            *value = json!(field_crypto::encrypt_field(public_key, path, value)?);
+        }
+    }
+    Ok(data)
+}
+
+/// A field a request would change, listed by `?dryRun=true` instead of changing it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedField {
+    pub path: String,
+    /// `encrypt`, `decrypt`, a field action, or `report` for personal data only reported.
+    pub action: &'static str,
+    /// The rule the field matched, or what it looks like.
+    pub reason: String,
+    /// Why changing the field would fail.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Why encrypting a value would fail, found without encrypting it: PKCS#1 v1.5 leaves room for `k - 11` bytes
+/// with a `k` byte key. A field takes its JSON Pointer `path` with it, a token vault entry (`None`) does not.
+pub fn encryption_error(
+    public_key: &RsaPublicKey,
+    path: Option<&str>,
+    value: &Value,
+) -> Option<String> {
+    let length = match path {
+        Some(path) => field_crypto::field_plaintext(path, value).len(),
+        None => Zeroizing::new(value.to_string()).len(),
+    };
+    let max = public_key.size() - 11;
+    (length > max).then(|| format!("too large for RSA: {} bytes, at most {}", length, max))
+}
+
+/// The fields `apply_field_actions` would change, with `vault` telling if tokens come from a vault, which
+/// encrypts the values.
+pub fn plan_field_actions(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    actions: &BTreeMap<String, FieldAction>,
+    vault: bool,
+) -> Vec<PlannedField> {
+    fn walk(
+        data: &Value,
+        path: &str,
+        public_key: &RsaPublicKey,
+        actions: &BTreeMap<String, FieldAction>,
+        vault: bool,
+        planned: &mut Vec<PlannedField>,
+    ) {
+        match data {
+            Value::Array(vec) => {
+                for (index, entry) in vec.iter().enumerate() {
+                    let path = pointer_push(path, &index.to_string());
+                    walk(entry, &path, public_key, actions, vault, planned);
+                }
+            }
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let path = pointer_push(path, key);
+                    let Some(action) = actions.get(key) else {
+                        walk(value, &path, public_key, actions, vault, planned);
+                        continue;
+                    };
+                    let error = match action {
+                        FieldAction::Encrypt => encryption_error(public_key, Some(&path), value),
+                        FieldAction::Tokenize if vault => encryption_error(public_key, None, value),
+                        _ => None,
+                    };
+                    planned.push(PlannedField {
+                        path,
+                        action: action.name(),
+                        reason: format!("{} is configured", key),
+                        error,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut planned = vec![];
+    walk(payload, "", public_key, actions, vault, &mut planned);
+    planned
 }
 
-/// Accept a Value containing the fields to encrypt. Returns the vector containing those fields.
-pub fn get_config(payload: &Value) -> Result<Vec<String>, String> {
-    Ok(payload
+/// The fields `encrypt_paths` would encrypt, for `reason`.
+pub fn plan_paths(
+    payload: &Value,
+    public_key: &RsaPublicKey,
+    paths: &[String],
+    reason: &str,
+) -> Vec<PlannedField> {
+    let mut planned: Vec<PlannedField> = vec![];
+    for path in paths {
+        let inside_planned = planned
+            .iter()
+            .any(|field| path.starts_with(&format!("{}/", field.path)));
+        if let Some(value) = payload.pointer(path).filter(|_| !inside_planned) {
+            planned.push(PlannedField {
+                path: path.clone(),
+                action: "encrypt",
+                reason: reason.to_string(),
+                error: encryption_error(public_key, Some(path), value),
+            });
+        }
+    }
+    planned
+}
+
+/// The strings `detect_and_decrypt_fields` would decrypt: those that look like ciphertexts of the key, with
+/// an error for those `allowed` refuses. Fails like it with more than `max_attempts` strings to try.
+pub fn plan_decryption(
+    data: &Value,
+    public_key: &RsaPublicKey,
+    allowed: &dyn Fn(&str) -> bool,
+    max_attempts: usize,
+) -> Result<Vec<PlannedField>, String> {
+    let attempts = decryption_attempts(data, allowed);
+    if attempts > max_attempts {
+        return Err(format!(
+            "too many fields to decrypt: {} (at most {})",
+            attempts, max_attempts
+        ));
+    }
+    let mut planned = vec![];
+    visit_strings(&mut data.clone(), "", &mut |path, entry| {
+        if entry
+            .as_str()
+            .is_some_and(|string| field_crypto::looks_encrypted(string, public_key))
+        {
+            planned.push(PlannedField {
+                path: path.to_string(),
+                action: "decrypt",
+                reason: "ciphertext".to_string(),
+                error: (!allowed(path)).then(|| "not allowed to decrypt".to_string()),
+            });
+        }
+    });
+    Ok(planned)
+}
+
+/// A `fieldActions` entry of a `/config` request.
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "camelCase", deny_unknown_fields)]
+enum ActionSettings {
+    // struct variants, so unknown fields are refused
+    Encrypt {},
+    Redact {},
+    Mask { pattern: String },
+    Hash { salt: String },
+    Tokenize {},
+}
+
+impl From<ActionSettings> for FieldAction {
+    fn from(settings: ActionSettings) -> Self {
+        match settings {
+            ActionSettings::Encrypt {} => FieldAction::Encrypt,
+            ActionSettings::Redact {} => FieldAction::Redact,
+            ActionSettings::Mask { pattern } => FieldAction::Mask { pattern },
+            ActionSettings::Hash { salt } => FieldAction::Hash { salt },
+            ActionSettings::Tokenize {} => FieldAction::Tokenize,
+        }
+    }
+}
+
+/// Accept a Value containing the fields to encrypt, and optionally `fieldActions` to do something else with
+/// other fields. Returns the action of each field.
+pub fn get_config(payload: &Value) -> Result<BTreeMap<String, FieldAction>, String> {
+    let mut actions = payload
         .get("fieldsToEncrypt")
         .ok_or("missing fieldsToEncrypt")?
         .as_array()
@@ -186,7 +737,156 @@ pub fn get_config(payload: &Value) -> Result<Vec<String>, String> {
         .map(|x| {
             x.as_str()
                 .ok_or("fieldsToEncrypt must be a string")
-                .map(|x| x.to_string())
+                .map(|x| (x.to_string(), FieldAction::Encrypt))
         })
-        .collect::<Result<Vec<_>, _>>()?)
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    if let Some(field_actions) = payload.get("fieldActions") {
+        let field_actions: BTreeMap<String, ActionSettings> =
+            serde_json::from_value(field_actions.clone())
+                .map_err(|e| format!("invalid fieldActions: {}", e))?;
+        for (field, action) in field_actions {
+            if actions.contains_key(&field) {
+                return Err(format!(
+                    "{} is both in fieldsToEncrypt and fieldActions",
+                    field
+                ));
+            }
+            actions.insert(field, action.into());
+        }
+    }
+    Ok(actions)
+}
+
+/// The digest whose signature is the tokenization key. No input is known to hash to it, so no `/sign` or
+/// `/sign/raw` request can make the server sign it.
+const TOKENIZATION_DIGEST: &[u8; 32] = b"field tokenization key, no hash.";
+
+/// The key of the keyed hashes of values: the signature of `TOKENIZATION_DIGEST`, which only the private key
+/// can make.
+pub fn tokenization_key(key: &dyn KeyProvider) -> Result<Zeroizing<Vec<u8>>, String> {
+    Ok(Zeroizing::new(key.sign(TOKENIZATION_DIGEST)?))
+}
+
+/// HMAC-SHA256 of `data` under `key`.
+pub fn keyed_hash(key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| e.to_string())?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Apply the field actions of `/config`, see `field_crypto::actions`. Tokens come from `vault` if there is one.
+/// Otherwise a token is `tok_` and the hex of the keyed hash of the value's text, truncated to 16 bytes, so a
+/// value gets the same token from every server with the key.
+pub fn apply_field_actions(
+    payload: &Value,
+    key: &dyn KeyProvider,
+    actions: &BTreeMap<String, FieldAction>,
+    vault: Option<&TokenVault>,
+) -> Result<Value, String> {
+    let mut token_key: Option<Zeroizing<Vec<u8>>> = None;
+    detect_and_apply(payload, key.public_key(), actions, &mut |value| {
+        if let Some(vault) = vault {
+            return vault.tokenize(value, key);
+        }
+        let token_key = match &mut token_key {
+            Some(token_key) => token_key,
+            None => token_key.insert(tokenization_key(key)?),
+        };
+        let token = keyed_hash(token_key, text(value).as_bytes())?;
+        Ok(format!(
+            "tok_{}",
+            token[..16]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        ))
+    })
+}
+
+/// Call `visit` with the JSON Pointer of every string of a field `actions` tokenizes, where `detect_and_apply`
+/// puts tokens.
+fn visit_tokens(
+    data: &mut Value,
+    path: &str,
+    actions: &BTreeMap<String, FieldAction>,
+    visit: &mut dyn FnMut(&str, &mut Value),
+) {
+    match data {
+        Value::Array(vec) => {
+            for (index, entry) in vec.iter_mut().enumerate() {
+                visit_tokens(
+                    entry,
+                    &pointer_push(path, &index.to_string()),
+                    actions,
+                    visit,
+                );
+            }
+        }
+        Value::Object(map) => {
+            for (key, entry) in map.iter_mut() {
+                let path = pointer_push(path, key);
+                match actions.get(key) {
+                    Some(FieldAction::Tokenize) if entry.is_string() => visit(&path, entry),
+                    Some(FieldAction::Tokenize) => {}
+                    _ => visit_tokens(entry, &path, actions, visit),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// JSON Pointers of the strings in either document that `actions` tokenizes and whose value differs in the
+/// other one: the fields tokenized going from `before` to `after`, or detokenized.
+pub fn changed_tokens(
+    before: &Value,
+    after: &Value,
+    actions: &BTreeMap<String, FieldAction>,
+) -> Vec<String> {
+    let mut paths = vec![];
+    for data in [before, after] {
+        visit_tokens(&mut data.clone(), "", actions, &mut |path, _| {
+            paths.push(path.to_string())
+        });
+    }
+    paths.sort();
+    paths.dedup();
+    paths.retain(|path| before.pointer(path) != after.pointer(path));
+    paths
+}
+
+/// Replace the tokens of the fields `actions` tokenizes with their values from `vault`, where `allowed`
+/// accepts their JSON Pointer. Strings that are not in the vault are left as they are. Fails without
+/// detokenizing anything if more than `max_lookups` strings would be looked up.
+pub fn detect_and_detokenize<'a>(
+    data: &Value,
+    vault: &TokenVault,
+    key_by_id: &dyn Fn(&str) -> Option<&'a dyn KeyProvider>,
+    actions: &BTreeMap<String, FieldAction>,
+    allowed: &dyn Fn(&str) -> bool,
+    max_lookups: usize,
+) -> Result<Value, String> {
+    let mut lookups = 0;
+    visit_tokens(&mut data.clone(), "", actions, &mut |path, _| {
+        lookups += allowed(path) as usize
+    });
+    if lookups > max_lookups {
+        return Err(format!(
+            "too many fields to detokenize: {} (at most {})",
+            lookups, max_lookups
+        ));
+    }
+    let mut data = data.clone();
+    let mut result = Ok(());
+    visit_tokens(&mut data, "", actions, &mut |path, entry| {
+        if result.is_err() || !allowed(path) {
+            return;
+        }
+        match vault.detokenize(entry.as_str().unwrap_or_default(), key_by_id) {
+            Ok(Some(value)) => *entry = value,
+            Ok(None) => {}
+            Err(e) => result = Err(e),
+        }
+    });
+    result.map(|_| data)
 }
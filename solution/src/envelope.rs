@@ -0,0 +1,199 @@
+//! Signed envelopes: a signature over the document together with when it was issued, when it expires, a
+//! random nonce and an optional audience, so a captured signed payload cannot be verified again once expired,
+//! more than once, or by another audience.
+//!
+//! Nonces are remembered until their envelope expires, in a store of bounded size. When the store is full the
+//! nonces expiring first are forgotten, and envelopes expiring before them are refused from then on, since
+//! their reuse could no longer be detected. The store is only in memory: after a restart, an envelope verified
+//! before it verifies once more until it expires, so `maxTtl` bounds how long a replay stays possible.
+//!
+//! With authentication, the verifier an audience is checked against is the caller's id, not a name the caller
+//! gives (see `routes::verify`).
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far, in seconds, the issue time of an envelope may be ahead of the server's clock.
+const MAX_CLOCK_SKEW: u64 = 60;
+
+/// The `envelopes` block of the settings file.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct EnvelopeSettings {
+    /// Lifetime in seconds of an envelope when `/sign` is not given a `ttl`.
+    pub default_ttl: u64,
+    /// Longest lifetime `/sign` accepts.
+    pub max_ttl: u64,
+    /// Nonces remembered at most.
+    pub max_nonces: usize,
+    /// Refuse `/verify` requests with a bare signature or field signatures, so every signature can only be used
+    /// once.
+    pub require_envelope: bool,
+}
+
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        EnvelopeSettings {
+            default_ttl: 300,
+            max_ttl: 86_400,
+            max_nonces: 100_000,
+            require_envelope: false,
+        }
+    }
+}
+
+/// The signed metadata of an envelope. The signature covers `{"data": <plaintext>, "envelope": <this>}`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Envelope {
+    /// Unix seconds.
+    pub issued_at: u64,
+    /// Unix seconds after which the envelope is refused.
+    pub expires_at: u64,
+    /// Base64 of 16 random bytes.
+    pub nonce: String,
+    /// Who may verify the envelope, anyone when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+impl Envelope {
+    /// A new envelope valid for `ttl` seconds from now.
+    pub fn new(ttl: u64, audience: Option<String>) -> Self {
+        let mut nonce = [0; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let issued_at = now();
+        Envelope {
+            issued_at,
+            expires_at: issued_at.saturating_add(ttl),
+            nonce: base64::encode(nonce),
+            audience,
+        }
+    }
+}
+
+/// Why a signature was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The request is malformed.
+    Invalid(String),
+    /// A bare signature where the settings require an envelope.
+    EnvelopeRequired,
+    Expired,
+    /// Issued in the future.
+    NotYetValid,
+    /// The nonce was already used, or is too old to tell.
+    Replayed,
+    /// The envelope is for another audience than the verifier's.
+    WrongAudience,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Invalid(e) => write!(f, "{}", e),
+            VerifyError::EnvelopeRequired => write!(f, "signature must be in an envelope"),
+            VerifyError::Expired => write!(f, "envelope expired"),
+            VerifyError::NotYetValid => write!(f, "envelope not yet valid"),
+            VerifyError::Replayed => write!(f, "envelope nonce already used"),
+            VerifyError::WrongAudience => write!(f, "envelope is for another audience"),
+        }
+    }
+}
+
+impl From<String> for VerifyError {
+    fn from(e: String) -> Self {
+        VerifyError::Invalid(e)
+    }
+}
+
+impl From<&str> for VerifyError {
+    fn from(e: &str) -> Self {
+        VerifyError::Invalid(e.to_string())
+    }
+}
+
+struct Nonces {
+    /// Expiry of each remembered nonce.
+    expiry: HashMap<String, u64>,
+    /// The same, ordered by expiry.
+    by_expiry: BTreeSet<(u64, String)>,
+    /// Latest expiry of a nonce forgotten before it expired.
+    forgotten_until: u64,
+}
+
+/// Nonces of the envelopes verified so far, until they expire or the server restarts.
+pub struct NonceStore {
+    max_nonces: usize,
+    nonces: Mutex<Nonces>,
+}
+
+impl NonceStore {
+    pub fn new(max_nonces: usize) -> Self {
+        NonceStore {
+            max_nonces,
+            nonces: Mutex::new(Nonces {
+                expiry: HashMap::new(),
+                by_expiry: BTreeSet::new(),
+                forgotten_until: 0,
+            }),
+        }
+    }
+
+    /// Check the times and audience of an envelope, then use up its nonce. Only call once the signature
+    /// matched, so forged envelopes cannot use up nonces.
+    pub fn accept(&self, envelope: &Envelope, audience: Option<&str>) -> Result<(), VerifyError> {
+        let now = now();
+        if envelope.issued_at > now.saturating_add(MAX_CLOCK_SKEW) {
+            return Err(VerifyError::NotYetValid);
+        }
+        if envelope.expires_at < now {
+            return Err(VerifyError::Expired);
+        }
+        if envelope
+            .audience
+            .as_deref()
+            .is_some_and(|expected| audience != Some(expected))
+        {
+            return Err(VerifyError::WrongAudience);
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        if envelope.expires_at <= nonces.forgotten_until
+            || nonces.expiry.contains_key(&envelope.nonce)
+        {
+            return Err(VerifyError::Replayed);
+        }
+        // forget the expired nonces, then the ones expiring first while still full
+        while let Some((expires_at, nonce)) = nonces.by_expiry.iter().next().cloned() {
+            if expires_at >= now && nonces.expiry.len() < self.max_nonces {
+                break;
+            }
+            nonces.by_expiry.remove(&(expires_at, nonce.clone()));
+            nonces.expiry.remove(&nonce);
+            if expires_at >= now {
+                nonces.forgotten_until = nonces.forgotten_until.max(expires_at);
+            }
+        }
+        if envelope.expires_at <= nonces.forgotten_until {
+            return Err(VerifyError::Replayed);
+        }
+        nonces
+            .expiry
+            .insert(envelope.nonce.clone(), envelope.expires_at);
+        nonces
+            .by_expiry
+            .insert((envelope.expires_at, envelope.nonce.clone()));
+        Ok(())
+    }
+}
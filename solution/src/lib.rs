@@ -0,0 +1,116 @@
+use field_crypto::actions::FieldAction;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+pub mod audit;
+pub mod auth;
+pub mod crypto;
+pub mod envelope;
+pub mod keys;
+pub mod limits;
+pub mod memory;
+pub mod policy;
+pub mod provider;
+pub mod routes;
+pub mod schema;
+pub mod settings;
+pub mod tls;
+pub mod vault;
+
+#[derive(Clone)]
+pub struct AppState {
+    /// Shared by every worker, never copied.
+    key: Arc<dyn provider::KeyProvider>,
+    key_id: String,
+    /// Keys used before the current one, with their ids.
+    retired_keys: Vec<(String, Arc<dyn provider::KeyProvider>)>,
+    /// What `/encrypt` does with each field, set by `/config`.
+    field_actions: Arc<RwLock<BTreeMap<String, FieldAction>>>,
+    /// Schemas `/encrypt?schema=` selects the fields with, by name.
+    schemas: Arc<RwLock<BTreeMap<String, Arc<schema::Schema>>>>,
+    pii_detection: Option<settings::PiiDetection>,
+    /// Encrypted strings a single request may ask to decrypt.
+    max_decryptions: usize,
+    envelopes: envelope::EnvelopeSettings,
+    /// Nonces of the envelopes verified, shared by every worker.
+    nonces: Arc<envelope::NonceStore>,
+    /// Where `/tokenize` keeps the values of its tokens, none unless configured.
+    vault: Option<Arc<vault::TokenVault>>,
+}
+
+impl AppState {
+    /// State for a server using the given key, starting with no fields to encrypt and no limit on decryptions.
+    pub fn new(key: Box<dyn provider::KeyProvider>) -> Self {
+        AppState {
+            key_id: key.key_id(),
+            key: Arc::from(key),
+            retired_keys: vec![],
+            field_actions: Arc::new(RwLock::new(BTreeMap::new())),
+            schemas: Arc::new(RwLock::new(BTreeMap::new())),
+            pii_detection: None,
+            max_decryptions: usize::MAX,
+            envelopes: envelope::EnvelopeSettings::default(),
+            nonces: Arc::new(envelope::NonceStore::new(
+                envelope::EnvelopeSettings::default().max_nonces,
+            )),
+            vault: None,
+        }
+    }
+
+    /// The key named by `id` in a `/keys/{id}/...` path, once percent-decoded: its key id, or `current`.
+    fn key_by_id(&self, id: &str) -> Option<&dyn provider::KeyProvider> {
+        if id == "current" || id == self.key_id {
+            return Some(self.key.as_ref());
+        }
+        self.retired_keys
+            .iter()
+            .find(|(key_id, _)| *key_id == id)
+            .map(|(_, key)| key.as_ref())
+    }
+
+    /// Also accept ciphertexts made with these previous keys, where the request names the key or for
+    /// `/reencrypt`.
+    pub fn with_retired_keys(mut self, keys: Vec<Box<dyn provider::KeyProvider>>) -> Self {
+        self.retired_keys = keys
+            .into_iter()
+            .map(|key| (key.key_id(), Arc::from(key)))
+            .collect();
+        self
+    }
+
+    /// Lifetimes of signed envelopes and the size of the nonce store.
+    pub fn with_envelopes(mut self, settings: envelope::EnvelopeSettings) -> Self {
+        self.nonces = Arc::new(envelope::NonceStore::new(settings.max_nonces));
+        self.envelopes = settings;
+        self
+    }
+
+    /// Refuse requests that would decrypt more than `max` strings.
+    pub fn with_max_decryptions(mut self, max: usize) -> Self {
+        self.max_decryptions = max;
+        self
+    }
+
+    /// Keep the values of tokens in this vault, for `/tokenize` and `/detokenize`, and have `/encrypt` use its
+    /// tokens.
+    pub fn with_token_vault(mut self, vault: vault::TokenVault) -> Self {
+        self.vault = Some(Arc::new(vault));
+        self
+    }
+
+    /// Start with these schemas, more can be uploaded to `/schemas/{name}`.
+    pub fn with_schemas(self, schemas: BTreeMap<String, schema::Schema>) -> Self {
+        *self.schemas.write().unwrap() = schemas
+            .into_iter()
+            .map(|(name, schema)| (name, Arc::new(schema)))
+            .collect();
+        self
+    }
+
+    /// Look for personal data `/encrypt` leaves in clear, and report or encrypt it.
+    pub fn with_pii_detection(mut self, detection: Option<settings::PiiDetection>) -> Self {
+        self.pii_detection = detection;
+        self
+    }
+}
@@ -0,0 +1,230 @@
+//! Rate and concurrency limits for the routes decrypting with the private key. Every decryption is a full
+//! RSA operation whatever the input, so these routes are the cheapest way to load the server, and the ones
+//! a padding oracle attack would query.
+//!
+//! Clients are told apart by their authenticated id, or by their address when authentication is disabled,
+//! so the middleware must run inside `auth::Authentication`.
+
+use crate::auth::Caller;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::{ErrorServiceUnavailable, InternalError};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{Error, HttpMessage, HttpResponse};
+use futures_util::future::{ok, LocalBoxFuture, Ready};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Client buckets kept before the full ones are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Limits from the settings file. Every limit is optional, except the decryptions per request.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Requests per minute allowed to each client, in bursts of up to a minute's worth. Zero is refused when
+    /// the settings load, as it would never refill.
+    pub requests_per_minute: Option<NonZeroU32>,
+    /// Requests per minute allowed to all clients together.
+    pub global_requests_per_minute: Option<NonZeroU32>,
+    /// Requests handled at once, others are refused with 503. Zero is refused like a zero rate, as it would
+    /// refuse every request.
+    pub max_concurrent_requests: Option<NonZeroUsize>,
+    /// Encrypted strings a single request may ask to decrypt.
+    pub max_decryptions_per_request: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            requests_per_minute: None,
+            global_requests_per_minute: None,
+            max_concurrent_requests: None,
+            // a 4 KB body holds about 11 ciphertexts of a 2048 bit key
+            max_decryptions_per_request: 32,
+        }
+    }
+}
+
+/// A token bucket holding up to a minute of requests.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Bucket {
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long until one is available.
+    fn take(&mut self, per_minute: u32) -> Result<(), Duration> {
+        self.refill(per_minute);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) * 60.0 / per_minute as f64,
+            ))
+        }
+    }
+}
+
+struct State {
+    settings: LimitSettings,
+    global: Mutex<Bucket>,
+    clients: Mutex<HashMap<String, Bucket>>,
+    in_flight: AtomicUsize,
+}
+
+impl State {
+    /// Take a token from the client's and the global bucket.
+    fn admit(&self, client: String) -> Result<(), Duration> {
+        if let Some(per_minute) = self.settings.requests_per_minute.map(NonZeroU32::get) {
+            let mut clients = self.clients.lock().unwrap();
+            if clients.len() >= MAX_TRACKED_CLIENTS {
+                // a full bucket is the same as a new one
+                clients.retain(|_, bucket| {
+                    bucket.refill(per_minute);
+                    bucket.tokens < per_minute as f64
+                });
+            }
+            clients
+                .entry(client)
+                .or_insert_with(|| Bucket::new(per_minute))
+                .take(per_minute)?;
+        }
+        if let Some(per_minute) = self
+            .settings
+            .global_requests_per_minute
+            .map(NonZeroU32::get)
+        {
+            self.global.lock().unwrap().take(per_minute)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts a request as in flight until dropped.
+struct InFlight(Arc<State>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware factory. Clones share their limits, so one instance can wrap several routes.
+#[derive(Clone)]
+pub struct RateLimit {
+    state: Arc<State>,
+}
+
+impl RateLimit {
+    pub fn new(settings: LimitSettings) -> Self {
+        RateLimit {
+            state: Arc::new(State {
+                global: Mutex::new(Bucket::new(
+                    settings
+                        .global_requests_per_minute
+                        .map_or(0, NonZeroU32::get),
+                )),
+                clients: Mutex::new(HashMap::new()),
+                in_flight: AtomicUsize::new(0),
+                settings,
+            }),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            state: self.state.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    state: Arc<State>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let client = match req.extensions().get::<Caller>() {
+            Some(caller) => format!("caller {}", caller.id),
+            None => format!(
+                "address {}",
+                req.peer_addr()
+                    .map_or(String::new(), |addr| addr.ip().to_string())
+            ),
+        };
+        if let Err(wait) = self.state.admit(client) {
+            let response = HttpResponse::TooManyRequests()
+                .set_header(RETRY_AFTER, wait.as_secs() + 1)
+                .finish();
+            return Box::pin(async move {
+                Err(InternalError::from_response("rate limit exceeded", response).into())
+            });
+        }
+        let running = self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight(self.state.clone());
+        if self
+            .state
+            .settings
+            .max_concurrent_requests
+            .is_some_and(|max| running >= max.get())
+        {
+            return Box::pin(async move { Err(ErrorServiceUnavailable("server busy")) });
+        }
+        let response = self.service.borrow_mut().call(req);
+        Box::pin(async move {
+            let response = response.await;
+            drop(in_flight);
+            response
+        })
+    }
+}
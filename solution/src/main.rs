@@ -1,18 +1,16 @@
 use actix_web::FromRequest;
 use actix_web::{web, App, HttpServer, Responder};
-use rand::rngs::OsRng;
-use std::sync::Arc;
-use std::sync::RwLock;
+use interview_problem::policy::Policy;
+use interview_problem::settings::Settings;
+use interview_problem::{
+    audit, auth, limits, memory, provider, routes, schema, tls, vault, AppState,
+};
 
-mod crypto;
-mod routes;
-
-#[derive(Clone)]
-pub struct AppState {
-    public_key: rsa::RsaPublicKey,
-    private_key: rsa::RsaPrivateKey,
-    fields_to_encrypt: Arc<RwLock<Vec<String>>>,
-}
+/// Largest request body accepted, in bytes.
+const BODY_LIMIT: usize = 4096;
+/// Largest blob `/decrypt/blob` takes from an HMAC signed request, whose body is read before any of it is
+/// decrypted.
+const SIGNED_BLOB_LIMIT: usize = 64 * 1024 * 1024;
 
 async fn greet() -> impl Responder {
     "Bonjour! No need to look at this page. Go encrypt some things.".to_string()
@@ -20,27 +18,125 @@ async fn greet() -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let (public_key, private_key) = crypto::load_keys("pub_key", "priv_key", &mut OsRng);
+    let settings = Settings::load("settings.json").expect("failed to load settings");
+    let key = provider::from_settings(&settings.key).expect("failed to load keys");
     println!("Keys loaded / generated");
-    let data = AppState {
-        public_key,
-        private_key,
-        fields_to_encrypt: Arc::new(RwLock::new(vec![])),
+    let retired_keys = settings
+        .retired_keys
+        .iter()
+        .map(provider::retired_from_settings)
+        .collect::<Result<Vec<_>, _>>()
+        .expect("failed to load retired keys");
+    let authenticators = match &settings.credentials_file {
+        Some(file) => auth::load_credentials(file).expect("failed to load credentials"),
+        None => {
+            println!("No credentials file configured, authentication is disabled");
+            vec![]
+        }
+    };
+    let policy = match &settings.policy_file {
+        Some(_) if authenticators.is_empty() => {
+            panic!("a policy file needs a credentials file to identify callers")
+        }
+        Some(file) => Policy::load(file).expect("failed to load policy"),
+        None => Policy::default(),
     };
-    HttpServer::new(move || {
+    let authentication =
+        auth::Authentication::new(authenticators, policy, BODY_LIMIT, SIGNED_BLOB_LIMIT);
+    let audit_log = settings
+        .audit_log_file
+        .as_ref()
+        .map(|file| audit::AuditLog::open(file, key.key_id()).expect("failed to open audit log"));
+    let audit = audit::Audit::new(audit_log);
+    let vault = settings.token_vault_directory.as_ref().map(|directory| {
+        let key_by_id = |id: &str| {
+            std::iter::once(&key)
+                .chain(&retired_keys)
+                .find(|key| key.key_id() == id)
+                .map(|key| key.as_ref())
+        };
+        vault::TokenVault::open(directory, key.as_ref(), &key_by_id)
+            .expect("failed to open token vault")
+    });
+    let schemas = match &settings.schema_directory {
+        Some(directory) => schema::load_directory(directory).expect("failed to load schemas"),
+        None => Default::default(),
+    };
+    // one state for every worker, rather than a copy of the private key each
+    let state = AppState::new(key)
+        .with_retired_keys(retired_keys)
+        .with_envelopes(settings.envelopes.clone())
+        .with_max_decryptions(settings.limits.max_decryptions_per_request)
+        .with_schemas(schemas)
+        .with_pii_detection(settings.pii_detection);
+    let data = web::Data::new(match vault {
+        Some(vault) => state.with_token_vault(vault),
+        None => state,
+    });
+    if let Err(e) = memory::lock_current() {
+        println!(
+            "Could not lock the keys in memory, they may be swapped out: {}",
+            e
+        );
+    }
+    let rate_limit = limits::RateLimit::new(settings.limits.clone());
+    let app = move || {
         use routes::*;
         App::new()
-            .data(data.clone())
+            .app_data(data.clone())
+            .wrap(authentication.clone())
+            // outermost, to also record requests the authentication rejects
+            .wrap(audit.clone())
             // limit size of payload
-            .app_data(String::configure(|cfg| cfg.limit(4096)))
+            .app_data(String::configure(|cfg| cfg.limit(BODY_LIMIT)))
             .route("/", web::get().to(greet))
             .route("/encrypt", web::post().to(encrypt))
-            .route("/decrypt", web::post().to(decrypt))
+            .route("/encrypt/blob", web::post().to(encrypt_blob))
+            .route("/pii", web::post().to(pii))
+            .service(
+                web::resource("/decrypt/blob")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(decrypt_blob)),
+            )
+            .service(
+                web::resource("/decrypt")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(decrypt)),
+            )
+            .route("/tokenize", web::post().to(tokenize))
+            .service(
+                web::resource("/detokenize")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(detokenize)),
+            )
             .route("/sign", web::post().to(sign))
-            .route("/verify", web::post().to(verify))
+            .route("/sign/raw", web::post().to(sign_raw))
+            .route("/verify/raw", web::post().to(verify_raw))
+            .service(
+                web::resource("/verify")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(verify)),
+            )
             .route("/config", web::post().to(config))
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+            .route("/schemas/{name}", web::post().to(upload_schema))
+            .route("/keys/{id}/wrap", web::post().to(wrap))
+            .service(
+                web::resource("/keys/{id}/unwrap")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(unwrap)),
+            )
+            .route("/datakey", web::post().to(datakey))
+            .service(
+                web::resource("/reencrypt")
+                    .wrap(rate_limit.clone())
+                    .route(web::post().to(reencrypt)),
+            )
+    };
+    match &settings.tls {
+        Some(tls_settings) => {
+            let config = tls::server_config(tls_settings).expect("failed to configure TLS");
+            tls::serve(app, config, ("0.0.0.0", 8080))?.await
+        }
+        None => HttpServer::new(app).bind(("0.0.0.0", 8080))?.run().await,
+    }
 }
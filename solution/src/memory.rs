@@ -0,0 +1,18 @@
+//! Keeping key material out of swap. The private key is loaded before the workers start, so locking the pages
+//! mapped at that point covers it. Later allocations are not locked, which would make every allocation fail
+//! once the locked memory limit is reached.
+
+/// Lock every page currently mapped by the process into memory.
+#[cfg(unix)]
+pub fn lock_current() -> Result<(), String> {
+    // SAFETY: mlockall only changes how the kernel pages the process's memory
+    match unsafe { libc::mlockall(libc::MCL_CURRENT) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error().to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lock_current() -> Result<(), String> {
+    Err("not supported on this platform".to_string())
+}
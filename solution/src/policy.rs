@@ -0,0 +1,156 @@
+//! Role based authorization. A policy file maps role names to the endpoints, tenants and field paths they
+//! allow; callers get roles from the credentials file. Checked by the authentication middleware before the
+//! handlers run, which then receive the resulting `Grant`.
+
+use crate::auth::{Caller, Permission};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Header naming the tenant a request acts for.
+pub const TENANT_HEADER: &str = "X-Tenant";
+
+/// A path pattern over JSON Pointer segments. `*` matches one segment and `**` any number of segments.
+/// A pattern without a leading `/` is a field name matched at any depth, like `fieldsToEncrypt`.
+#[derive(Clone, Debug)]
+pub struct FieldPattern(Vec<String>);
+
+impl FieldPattern {
+    pub fn parse(pattern: &str) -> Self {
+        match pattern.strip_prefix('/') {
+            Some(pointer) => FieldPattern(pointer.split('/').map(unescape).collect()),
+            None => FieldPattern(vec!["**".to_string(), pattern.to_string()]),
+        }
+    }
+
+    /// Whether the JSON Pointer `path` matches the pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        fn matches(pattern: &[String], path: &[String]) -> bool {
+            match (pattern.first().map(String::as_str), path.first()) {
+                (None, None) => true,
+                (Some("**"), _) => {
+                    matches(&pattern[1..], path)
+                        || (!path.is_empty() && matches(pattern, &path[1..]))
+                }
+                (Some(segment), Some(key)) => {
+                    (segment == "*" || segment == key) && matches(&pattern[1..], &path[1..])
+                }
+                _ => false,
+            }
+        }
+        let path = path
+            .strip_prefix('/')
+            .map(|pointer| pointer.split('/').map(unescape).collect::<Vec<_>>())
+            .unwrap_or_default();
+        matches(&self.0, &path)
+    }
+}
+
+fn unescape(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+/// What a role allows.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Role {
+    #[serde(default)]
+    pub endpoints: Vec<Permission>,
+    /// Tenants the role may act for, `*` for any. A request without a tenant needs `*`.
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    /// Field patterns the role may decrypt. Every field if not set.
+    #[serde(default)]
+    pub fields: Option<Vec<String>>,
+}
+
+impl Role {
+    fn allows_tenant(&self, tenant: Option<&str>) -> bool {
+        self.tenants
+            .iter()
+            .any(|allowed| allowed == "*" || Some(allowed.as_str()) == tenant)
+    }
+}
+
+/// The outcome of a successful authorization, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct Grant {
+    pub tenant: Option<String>,
+    /// Fields the caller may see decrypted, every field if `None`.
+    pub fields: Option<Vec<FieldPattern>>,
+}
+
+impl Grant {
+    /// Whether the field at the JSON Pointer `path` may be decrypted.
+    pub fn allows_field(&self, path: &str) -> bool {
+        self.fields
+            .as_ref()
+            .is_none_or(|fields| fields.iter().any(|field| field.matches(path)))
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Policy {
+    pub roles: HashMap<String, Role>,
+}
+
+impl Policy {
+    /// Read a policy file: `{"roles": {"<name>": Role}}`.
+    pub fn load<T: AsRef<Path>>(file: T) -> Result<Policy, String> {
+        let data = fs::read_to_string(&file)
+            .map_err(|e| format!("failed to read {}: {}", file.as_ref().display(), e))?;
+        serde_json::from_str(&data)
+            .map_err(|e| format!("invalid policy in {}: {}", file.as_ref().display(), e))
+    }
+
+    /// Check that `caller` may use the endpoint needing `permission` for `tenant`.
+    /// Permissions given directly in the credentials are unrestricted, roles may restrict tenants and fields.
+    pub fn authorize(
+        &self,
+        caller: &Caller,
+        permission: Permission,
+        tenant: Option<&str>,
+    ) -> Result<Grant, String> {
+        if caller.permissions.contains(&permission) {
+            return Ok(Grant {
+                tenant: tenant.map(str::to_string),
+                fields: None,
+            });
+        }
+        let roles = caller
+            .roles
+            .iter()
+            .filter_map(|name| self.roles.get(name))
+            .filter(|role| role.endpoints.contains(&permission) && role.allows_tenant(tenant))
+            .collect::<Vec<_>>();
+        if roles.is_empty() {
+            return Err(match tenant {
+                Some(tenant) => format!(
+                    "{} does not have the {:?} permission for tenant {}",
+                    caller.id, permission, tenant
+                ),
+                None => format!(
+                    "{} does not have the {:?} permission",
+                    caller.id, permission
+                ),
+            });
+        }
+        let fields = if roles.iter().any(|role| role.fields.is_none()) {
+            None
+        } else {
+            Some(
+                roles
+                    .iter()
+                    .flat_map(|role| role.fields.iter().flatten())
+                    .map(|field| FieldPattern::parse(field))
+                    .collect(),
+            )
+        };
+        Ok(Grant {
+            tenant: tenant.map(str::to_string),
+            fields,
+        })
+    }
+}
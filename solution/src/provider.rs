@@ -0,0 +1,325 @@
+//! Where the private key lives. The server only needs the public key and two private key operations, so the
+//! key can stay in memory (`SoftwareKey`, loaded from the key files, an environment variable or the OS
+//! keyring) or in a separate process holding it (`AgentKey`, over a Unix socket).
+
+use crate::crypto;
+use crate::keys;
+use rand::rngs::OsRng;
+use rsa::{PaddingScheme, RsaPrivateKey, RsaPublicKey};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// The operations the server does with its key pair.
+pub trait KeyProvider: Send + Sync {
+    fn public_key(&self) -> &RsaPublicKey;
+
+    /// Identifier of the key, its fingerprint.
+    fn key_id(&self) -> String {
+        field_crypto::fingerprint(self.public_key())
+    }
+
+    /// RSA PKCS#1 v1.5 signature of a SHA256 digest, without a DigestInfo prefix.
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// RSA PKCS#1 v1.5 decryption of one block. The error does not tell why decryption failed.
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String>;
+}
+
+/// Where to get the key from, the `key` block of the settings file.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase", deny_unknown_fields)]
+pub enum KeySettings {
+    /// Key files, generated on first start if they do not exist.
+    #[serde(rename_all = "camelCase")]
+    File {
+        public_key_file: PathBuf,
+        private_key_file: PathBuf,
+    },
+    /// A private key (PKCS#8 or PKCS#1 PEM) in an environment variable.
+    Env { variable: String },
+    /// A private key (PEM) stored as the password of an OS keyring entry. Needs the `keyring` feature.
+    Keyring { service: String, user: String },
+    /// A signing agent listening on a Unix socket, such as `crypt agent`.
+    Agent { socket: PathBuf },
+}
+
+impl Default for KeySettings {
+    fn default() -> Self {
+        KeySettings::File {
+            public_key_file: PathBuf::from("pub_key"),
+            private_key_file: PathBuf::from("priv_key"),
+        }
+    }
+}
+
+/// Open the key provider described by the settings.
+pub fn from_settings(settings: &KeySettings) -> Result<Box<dyn KeyProvider>, String> {
+    Ok(match settings {
+        KeySettings::File {
+            public_key_file,
+            private_key_file,
+        } => Box::new(SoftwareKey::from_files(public_key_file, private_key_file)),
+        _ => open(settings)?,
+    })
+}
+
+/// Open a retired key. Unlike `from_settings`, key files must already exist.
+pub fn retired_from_settings(settings: &KeySettings) -> Result<Box<dyn KeyProvider>, String> {
+    open(settings)
+}
+
+fn open(settings: &KeySettings) -> Result<Box<dyn KeyProvider>, String> {
+    Ok(match settings {
+        KeySettings::File {
+            public_key_file,
+            private_key_file,
+        } => {
+            let (_, private_key) = crypto::read_keys(public_key_file, private_key_file)
+                .ok_or_else(|| {
+                    format!(
+                        "could not read keys from {} and {}",
+                        public_key_file.display(),
+                        private_key_file.display()
+                    )
+                })?;
+            Box::new(SoftwareKey::new(private_key))
+        }
+        KeySettings::Env { variable } => Box::new(SoftwareKey::from_env(variable)?),
+        KeySettings::Keyring { service, user } => {
+            Box::new(SoftwareKey::from_keyring(service, user)?)
+        }
+        KeySettings::Agent { socket } => Box::new(AgentKey::connect(socket.clone())?),
+    })
+}
+
+/// A key pair held in this process's memory.
+pub struct SoftwareKey {
+    public_key: RsaPublicKey,
+    private_key: RsaPrivateKey,
+}
+
+impl SoftwareKey {
+    pub fn new(private_key: RsaPrivateKey) -> Self {
+        SoftwareKey {
+            public_key: RsaPublicKey::from(&private_key),
+            private_key,
+        }
+    }
+
+    /// A new key that is never written anywhere, for tests.
+    pub fn generate(bits: usize) -> Self {
+        SoftwareKey::new(crypto::generate_keys(&mut OsRng, bits).1)
+    }
+
+    /// The key in the key files, generating them first if needed, as `crypto::load_keys` does.
+    pub fn from_files<T: AsRef<Path> + Copy, S: AsRef<Path> + Copy>(
+        public_file: T,
+        private_file: S,
+    ) -> Self {
+        let (public_key, private_key) = crypto::load_keys(public_file, private_file, &mut OsRng);
+        SoftwareKey {
+            public_key,
+            private_key,
+        }
+    }
+
+    /// The private key in an environment variable, in any format `keys::import_private_key` reads as text.
+    pub fn from_env(variable: &str) -> Result<Self, String> {
+        let pem =
+            Zeroizing::new(std::env::var(variable).map_err(|e| format!("{}: {}", variable, e))?);
+        Ok(SoftwareKey::new(keys::import_private_key(pem.as_bytes())?))
+    }
+
+    /// The private key stored as the password of an OS keyring entry.
+    #[cfg(feature = "keyring")]
+    pub fn from_keyring(service: &str, user: &str) -> Result<Self, String> {
+        let pem = Zeroizing::new(
+            keyring::Entry::new(service, user)
+                .and_then(|entry| entry.get_password())
+                .map_err(|e| format!("keyring entry {}/{}: {}", service, user, e))?,
+        );
+        Ok(SoftwareKey::new(keys::import_private_key(pem.as_bytes())?))
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    pub fn from_keyring(_service: &str, _user: &str) -> Result<Self, String> {
+        Err("built without the keyring feature".to_string())
+    }
+}
+
+impl KeyProvider for SoftwareKey {
+    fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, String> {
+        self.private_key
+            .sign_blinded(&mut OsRng, PaddingScheme::new_pkcs1v15_sign(None), digest)
+            .map_err(|_| "failed to sign".to_string())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        // blinded so the timing does not depend on the key
+        self.private_key
+            .decrypt_blinded(
+                &mut OsRng,
+                PaddingScheme::new_pkcs1v15_encrypt(),
+                ciphertext,
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| "decryption failed".to_string())
+    }
+}
+
+/// A key held by a signing agent. Each operation is one connection to the agent's Unix socket, sending one
+/// JSON line `{"operation": "publicKey" | "sign" | "decrypt", "data": "<base64>"}` and reading back one
+/// JSON line `{"result": "<base64 or PEM>"}` or `{"error": "..."}`.
+pub struct AgentKey {
+    socket: PathBuf,
+    public_key: RsaPublicKey,
+}
+
+impl AgentKey {
+    /// Connect to the agent and fetch its public key.
+    pub fn connect(socket: PathBuf) -> Result<Self, String> {
+        let pem = agent::request(&socket, "publicKey", &[])?;
+        let public_key = field_crypto::public_key_from_pem(
+            std::str::from_utf8(&pem).map_err(|_| "agent sent an invalid public key")?,
+        )?;
+        Ok(AgentKey { socket, public_key })
+    }
+}
+
+impl KeyProvider for AgentKey {
+    fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    fn sign(&self, digest: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(agent::request(&self.socket, "sign", digest)?.to_vec())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        agent::request(&self.socket, "decrypt", ciphertext)
+    }
+}
+
+/// Both ends of the agent protocol.
+#[cfg(unix)]
+pub mod agent {
+    use super::KeyProvider;
+    use serde::{Deserialize, Serialize};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use zeroize::Zeroizing;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[derive(Serialize, Deserialize)]
+    struct Request {
+        operation: String,
+        #[serde(default)]
+        data: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Response {
+        Result(String),
+        Error(String),
+    }
+
+    /// Send one request to the agent at `socket`. Returns the decoded result.
+    pub fn request(
+        socket: &Path,
+        operation: &str,
+        data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, String> {
+        let fail = |e: std::io::Error| format!("agent {}: {}", socket.display(), e);
+        let mut stream = UnixStream::connect(socket).map_err(fail)?;
+        stream.set_read_timeout(Some(TIMEOUT)).map_err(fail)?;
+        stream.set_write_timeout(Some(TIMEOUT)).map_err(fail)?;
+        let request = Request {
+            operation: operation.to_string(),
+            data: base64::encode(data),
+        };
+        writeln!(stream, "{}", serde_json::to_string(&request).unwrap()).map_err(fail)?;
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(stream).read_line(&mut line).map_err(fail)?;
+        match serde_json::from_str(&line) {
+            Ok(Response::Result(result)) if operation == "publicKey" => {
+                Ok(Zeroizing::new(result.into_bytes()))
+            }
+            Ok(Response::Result(result)) => base64::decode(Zeroizing::new(result).as_bytes())
+                .map(Zeroizing::new)
+                .map_err(|_| "agent sent invalid base64".to_string()),
+            Ok(Response::Error(e)) => Err(e),
+            Err(_) => Err(format!("invalid response from agent {}", socket.display())),
+        }
+    }
+
+    fn respond(key: &dyn KeyProvider, line: &str) -> Response {
+        let request: Request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Response::Error(e.to_string()),
+        };
+        let data = match base64::decode(&request.data) {
+            Ok(data) => data,
+            Err(_) => return Response::Error("invalid base64".to_string()),
+        };
+        let result = match request.operation.as_str() {
+            "publicKey" => return Response::Result(crate::keys::export_pem(key.public_key())),
+            "sign" => key.sign(&data).map(base64::encode),
+            "decrypt" => key
+                .decrypt(&data)
+                .map(|plaintext| base64::encode(&*plaintext)),
+            operation => Err(format!("unknown operation {}", operation)),
+        };
+        match result {
+            Ok(result) => Response::Result(result),
+            Err(e) => Response::Error(e),
+        }
+    }
+
+    fn handle(key: &dyn KeyProvider, stream: UnixStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(&stream).read_line(&mut line)?;
+        let response = serde_json::to_string(&respond(key, &line)).unwrap();
+        writeln!(&stream, "{}", response)
+    }
+
+    /// Serve `key` on a new socket at `socket`, one thread per connection. Only returns on error.
+    pub fn serve(key: Arc<dyn KeyProvider>, socket: &Path) -> Result<(), String> {
+        let listener = UnixListener::bind(socket)
+            .map_err(|e| format!("failed to listen on {}: {}", socket.display(), e))?;
+        for stream in listener.incoming() {
+            let stream = stream.map_err(|e| e.to_string())?;
+            let key = key.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handle(key.as_ref(), stream) {
+                    eprintln!("agent connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+pub mod agent {
+    use std::path::Path;
+    use zeroize::Zeroizing;
+
+    pub fn request(
+        _socket: &Path,
+        _operation: &str,
+        _data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, String> {
+        Err("signing agents need Unix sockets".to_string())
+    }
+}
@@ -1,46 +1,605 @@
+use crate::audit::{changed_ciphertexts, record_fields};
+use crate::auth::Caller;
 use crate::crypto::*;
+use crate::envelope::{Envelope, VerifyError};
+use crate::policy::Grant;
+use crate::schema::Schema;
+use crate::settings::PiiDetection;
 use crate::AppState;
-use actix_web::{web, HttpResponse};
-use serde_json::json;
-
-pub async fn encrypt(text: String, data: web::Data<AppState>) -> Result<String, HttpResponse> {
-    let fields = data.fields_to_encrypt.read().unwrap();
-    Ok(detect_and_encrypt(
-        &serde_json::from_str(&text).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?,
-        &data.public_key,
-        &fields,
-    )
-    .map_err(|e| HttpResponse::BadRequest().body(e))?
-    .to_string())
+use actix_web::error::{BlockingError, ErrorBadRequest};
+use actix_web::http::header::{HeaderName, HeaderValue, ACCEPT};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use field_crypto::actions::FieldAction;
+use field_crypto::blob::{BlobDecryptor, BlobEncryptor, BlobStream};
+use field_crypto::format::Format;
+use field_crypto::pii::{find_pii, Pii};
+use field_crypto::rsa::RsaPublicKey;
+use futures_util::{stream, StreamExt};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// The format of a request body and the one to answer in.
+struct Formats {
+    request: Format,
+    response: Format,
+}
+
+impl Formats {
+    /// The body's format is its `Content-Type`, where any other than those of `Format` is read as JSON like
+    /// before, and the response's is the first `Accept` allows, preferring the body's.
+    fn of(req: &HttpRequest) -> Result<Self, HttpResponse> {
+        let request = Format::from_media_type(req.content_type()).unwrap_or(Format::Json);
+        let accept = match req.headers().get(ACCEPT).map(|value| value.to_str()) {
+            None => "*/*",
+            Some(Ok(accept)) if !accept.trim().is_empty() => accept,
+            Some(_) => return Err(HttpResponse::BadRequest().body("invalid Accept header")),
+        };
+        let mut ranges = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let media_type = parts.next()?.trim().to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())?;
+                (quality > 0.0).then_some((quality, media_type))
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranges
+            .iter()
+            .find_map(|(_, media_type)| match media_type.as_str() {
+                "*/*" | "application/*" => Some(request),
+                media_type => Format::from_media_type(media_type),
+            })
+            .map(|response| Formats { request, response })
+            .ok_or_else(|| {
+                HttpResponse::NotAcceptable()
+                    .body("expected application/json, application/cbor, application/msgpack, application/yaml or application/toml")
+            })
+    }
+
+    /// Decode the body, whose byte strings must be ciphertexts for `public_key`.
+    fn decode(&self, body: &[u8], public_key: &RsaPublicKey) -> Result<Value, HttpResponse> {
+        self.request
+            .decode(body, public_key)
+            .map_err(|e| HttpResponse::BadRequest().body(e))
+    }
+
+    /// Answer with a document, ciphertexts for `public_key` as byte strings where the format has them.
+    fn respond(
+        &self,
+        value: &Value,
+        public_key: &RsaPublicKey,
+    ) -> Result<HttpResponse, HttpResponse> {
+        self.answer(self.response.encode(value, public_key))
+    }
+
+    /// Answer with the request's document changed into `value`, keeping what the format allows of the body
+    /// where nothing changed, such as the comments of a TOML file.
+    fn respond_onto(
+        &self,
+        body: &[u8],
+        value: &Value,
+        public_key: &RsaPublicKey,
+    ) -> Result<HttpResponse, HttpResponse> {
+        if self.response != self.request {
+            return self.respond(value, public_key);
+        }
+        self.answer(self.response.encode_onto(body, value, public_key))
+    }
+
+    fn answer(&self, encoded: Result<Vec<u8>, String>) -> Result<HttpResponse, HttpResponse> {
+        Ok(HttpResponse::Ok()
+            .content_type(self.response.media_type())
+            .body(encoded.map_err(|e| HttpResponse::InternalServerError().body(e))?))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EncryptOptions {
+    /// Encrypt the fields this schema marks instead of those of `/config`, after validating the document.
+    #[serde(default)]
+    schema: Option<String>,
+    /// Comma separated sensitivity classes of the schema to encrypt, every one by default.
+    #[serde(default)]
+    classes: Option<String>,
+    /// List the fields that would be changed instead, see `PlannedField`.
+    #[serde(default)]
+    dry_run: bool,
 }
 
-pub async fn decrypt(text: String, data: web::Data<AppState>) -> Result<String, HttpResponse> {
-    Ok(serde_json::to_string(&detect_and_decrypt(
-        &serde_json::from_str(&text).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?,
-        &data.private_key,
-    ))
-    .map_err(|_| HttpResponse::BadRequest())?)
+/// The paths the schema of the request marks, after validating the document, if the request names one.
+fn schema_paths(
+    data: &AppState,
+    options: &EncryptOptions,
+    payload: &Value,
+) -> Result<Option<Vec<String>>, HttpResponse> {
+    let Some(name) = &options.schema else {
+        return Ok(None);
+    };
+    let schema = data
+        .schemas
+        .read()
+        .unwrap()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| HttpResponse::BadRequest().body(format!("unknown schema {}", name)))?;
+    schema
+        .validate(payload)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    let classes = options.classes.as_ref().map(|classes| {
+        classes
+            .split(',')
+            .map(|class| class.trim().to_string())
+            .collect::<Vec<_>>()
+    });
+    Ok(Some(schema.encrypted_paths(payload, classes.as_deref())))
 }
 
-pub async fn sign(text: String, data: web::Data<AppState>) -> Result<String, HttpResponse> {
-    Ok(json! {
+/// The fields `/encrypt` would change in a document, with the personal data it would find.
+fn plan_encryption(
+    payload: &Value,
+    data: &AppState,
+    options: &EncryptOptions,
+    schema_paths: Option<&[String]>,
+) -> Vec<PlannedField> {
+    let public_key = data.key.public_key();
+    let (mut planned, detected) = match (&options.schema, schema_paths) {
+        (Some(name), Some(paths)) => {
+            let covered = |path: &str| {
+                paths.iter().any(|encrypted| {
+                    path == encrypted || path.starts_with(&format!("{}/", encrypted))
+                })
+            };
+            let mut detected = find_pii(payload, public_key, &|_| false);
+            detected.retain(|path, _| !covered(path));
+            let reason = format!("marked by schema {}", name);
+            (plan_paths(payload, public_key, paths, &reason), detected)
+        }
+        _ => (
+            plan_field_actions(
+                payload,
+                public_key,
+                &data.field_actions.read().unwrap(),
+                data.vault.is_some(),
+            ),
+            unconfigured_pii(payload, data),
+        ),
+    };
+    if let Some(detection) = data.pii_detection {
+        let encrypt = detection == PiiDetection::Encrypt;
+        planned.extend(detected.into_iter().map(|(path, pii)| {
+            PlannedField {
+                error: payload
+                    .pointer(&path)
+                    .filter(|_| encrypt)
+                    .and_then(|value| encryption_error(public_key, Some(&path), value)),
+                path,
+                action: if encrypt { "encrypt" } else { "report" },
+                reason: format!("detected {}", pii.name()),
+            }
+        }));
+    }
+    planned
+}
+
+pub async fn encrypt(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+    options: web::Query<EncryptOptions>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let schema_paths = schema_paths(&data, &options, &payload)?;
+    if options.dry_run {
+        let planned = plan_encryption(&payload, &data, &options, schema_paths.as_deref());
+        return formats.respond(&json!({ "fields": planned }), data.key.public_key());
+    }
+    // tokens made without a vault are keyed with a signature
+    let mut encrypted = blocking({
+        let (data, payload) = (data.clone(), payload.clone());
+        move || match &schema_paths {
+            Some(paths) => encrypt_paths(&payload, data.key.public_key(), paths),
+            None => apply_field_actions(
+                &payload,
+                data.key.as_ref(),
+                &data.field_actions.read().unwrap(),
+                data.vault.as_deref(),
+            ),
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    let detected = match data.pii_detection {
+        Some(_) if options.schema.is_some() => {
+            find_pii(&encrypted, data.key.public_key(), &|_| false)
+        }
+        Some(_) => unconfigured_pii(&encrypted, &data),
+        None => BTreeMap::new(),
+    };
+    if data.pii_detection == Some(PiiDetection::Encrypt) {
+        let paths = detected.keys().cloned().collect::<Vec<_>>();
+        encrypted = encrypt_paths(&encrypted, data.key.public_key(), &paths)
+            .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    }
+    record_fields(
+        &req,
+        changed_ciphertexts(&payload, &encrypted, data.key.public_key()),
+    );
+    let mut response = formats.respond_onto(&body, &encrypted, data.key.public_key())?;
+    if !detected.is_empty() {
+        response.headers_mut().insert(
+            HeaderName::from_static("x-pii-detected"),
+            HeaderValue::from_str(&pii_header(&detected)).expect("percent-encoded header"),
+        );
+    }
+    Ok(response)
+}
+
+/// The personal data in a document outside of the fields `/config` has an action for.
+fn unconfigured_pii(payload: &Value, data: &AppState) -> BTreeMap<String, Pii> {
+    let actions = data.field_actions.read().unwrap();
+    find_pii(payload, data.key.public_key(), &|key| {
+        actions.contains_key(key)
+    })
+}
+
+/// `kind=pointer` pairs, separated by commas, with the commas, spaces, `%` and bytes outside of visible ASCII
+/// of the pointers percent-encoded.
+fn pii_header(detected: &BTreeMap<String, Pii>) -> String {
+    detected
+        .iter()
+        .map(|(path, pii)| {
+            let path = path
+                .bytes()
+                .map(|byte| match byte {
+                    b',' | b'%' => format!("%{:02X}", byte),
+                    0x21..=0x7e => (byte as char).to_string(),
+                    byte => format!("%{:02X}", byte),
+                })
+                .collect::<String>();
+            format!("{}={}", pii.name(), path)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The personal data `/encrypt` would leave in clear in a document, by JSON Pointer of the field, see
+/// `field_crypto::pii`: `{"detected": {"/contact": "email"}}`.
+pub async fn pii(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let detected = unconfigured_pii(&payload, &data);
+    record_fields(&req, detected.keys().cloned().collect());
+    let detected = detected
+        .into_iter()
+        .map(|(path, pii)| (path, json!(pii.name())))
+        .collect::<serde_json::Map<_, _>>();
+    formats.respond(&json!({ "detected": detected }), data.key.public_key())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DecryptOptions {
+    /// List the fields that would be decrypted instead, see `PlannedField`.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+pub async fn decrypt(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+    grant: Option<web::ReqData<Grant>>,
+    options: web::Query<DecryptOptions>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let grant = grant.map(web::ReqData::into_inner);
+    let allowed = move |path: &str| grant.as_ref().is_none_or(|grant| grant.allows_field(path));
+    if options.dry_run {
+        let planned = plan_decryption(
+            &payload,
+            data.key.public_key(),
+            &allowed,
+            data.max_decryptions,
+        )
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+        return formats.respond(&json!({ "fields": planned }), data.key.public_key());
+    }
+    let decrypted = blocking({
+        let (data, payload) = (data.clone(), payload.clone());
+        move || {
+            detect_and_decrypt_fields(&payload, data.key.as_ref(), &allowed, data.max_decryptions)
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    record_fields(
+        &req,
+        changed_ciphertexts(&payload, &decrypted, data.key.public_key()),
+    );
+    formats.respond_onto(&body, &decrypted, data.key.public_key())
+}
+
+/// The fields `/config` tokenizes.
+fn tokenized_fields(data: &AppState) -> BTreeMap<String, FieldAction> {
+    data.field_actions
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, action)| **action == FieldAction::Tokenize)
+        .map(|(field, action)| (field.clone(), action.clone()))
+        .collect()
+}
+
+fn no_vault() -> HttpResponse {
+    HttpResponse::BadRequest().body("no token vault configured")
+}
+
+/// Replace the fields `/config` tokenizes with tokens from the vault, leaving the others as they are.
+pub async fn tokenize(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    data.vault.as_ref().ok_or_else(no_vault)?;
+    let actions = tokenized_fields(&data);
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let tokenized = blocking({
+        let (data, payload, actions) = (data.clone(), payload.clone(), actions.clone());
+        move || {
+            apply_field_actions(
+                &payload,
+                data.key.as_ref(),
+                &actions,
+                Some(data.vault.as_deref().ok_or("no token vault configured")?),
+            )
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    record_fields(&req, changed_tokens(&payload, &tokenized, &actions));
+    formats.respond_onto(&body, &tokenized, data.key.public_key())
+}
+
+/// Replace the tokens of the fields `/config` tokenizes with their values from the vault.
+pub async fn detokenize(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+    grant: Option<web::ReqData<Grant>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    data.vault.as_ref().ok_or_else(no_vault)?;
+    let actions = tokenized_fields(&data);
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let grant = grant.map(web::ReqData::into_inner);
+    let detokenized = blocking({
+        let (data, payload, actions) = (data.clone(), payload.clone(), actions.clone());
+        move || {
+            detect_and_detokenize(
+                &payload,
+                data.vault.as_deref().ok_or("no token vault configured")?,
+                &|id| data.key_by_id(id),
+                &actions,
+                &|path| grant.as_ref().is_none_or(|grant| grant.allows_field(path)),
+                data.max_decryptions,
+            )
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    record_fields(&req, changed_tokens(&payload, &detokenized, &actions));
+    formats.respond_onto(&body, &detokenized, data.key.public_key())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SignOptions {
+    /// Sign each field on its own rather than the whole document.
+    #[serde(default)]
+    per_field: bool,
+    /// Sign the document in an envelope, see `envelope::Envelope`.
+    #[serde(default)]
+    envelope: bool,
+    /// Lifetime of the envelope in seconds.
+    #[serde(default)]
+    ttl: Option<u64>,
+    /// Who may verify the envelope.
+    #[serde(default)]
+    audience: Option<String>,
+}
+
+pub async fn sign(
+    req: HttpRequest,
+    body: Bytes,
+    options: web::Query<SignOptions>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    if options.per_field && options.envelope {
+        return Err(HttpResponse::BadRequest().body("give either perField or envelope"));
+    }
+    let public_key = data.key.public_key();
+    if options.per_field {
+        let payload = formats.decode(&body, data.key.public_key())?;
+        let signatures = blocking({
+            let data = data.clone();
+            move || get_field_signatures(&payload, data.key.as_ref())
+        })
+        .await?
+        .map_err(|e| HttpResponse::InternalServerError().body(e))?;
+        return formats.respond(&json!({ "signatures": signatures }), public_key);
+    }
+    if options.envelope {
+        let payload = formats.decode(&body, data.key.public_key())?;
+        let ttl = options.ttl.unwrap_or(data.envelopes.default_ttl);
+        if ttl > data.envelopes.max_ttl {
+            return Err(HttpResponse::BadRequest()
+                .body(format!("ttl must be at most {}", data.envelopes.max_ttl)));
+        }
+        let envelope = Envelope::new(ttl, options.audience.clone());
+        let signature = blocking({
+            let (data, envelope) = (data.clone(), envelope.clone());
+            move || get_envelope_signature(&payload, &envelope, data.key.as_ref())
+        })
+        .await?
+        .map_err(|e| HttpResponse::InternalServerError().body(e))?;
+        return formats.respond(
+            &json!({ "signature": signature, "envelope": envelope }),
+            public_key,
+        );
+    }
+    if options.ttl.is_some() || options.audience.is_some() {
+        return Err(HttpResponse::BadRequest().body("ttl and audience need envelope=true"));
+    }
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let signature = blocking({
+        let data = data.clone();
+        move || get_signature(&payload, data.key.as_ref())
+    })
+    .await?
+    .map_err(|e| HttpResponse::InternalServerError().body(e))?;
+    formats.respond(&json!({ "signature": signature }), public_key)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct VerifyOptions {
+    /// The verifier, which must match the audience of an envelope that has one. With authentication it is
+    /// the caller's id, which this may only repeat.
+    #[serde(default)]
+    audience: Option<String>,
+}
+
+/// Checks a whole document `signature`, answering 204 or a bare 400, or per field `signatures`, answering
+/// which fields verified.
+pub async fn verify(
+    req: HttpRequest,
+    body: Bytes,
+    options: web::Query<VerifyOptions>,
+    data: web::Data<AppState>,
+    grant: Option<web::ReqData<Grant>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let grant = grant.map(web::ReqData::into_inner);
+    let allowed = move |path: &str| grant.as_ref().is_none_or(|grant| grant.allows_field(path));
+    // field signatures are never in an envelope
+    if data.envelopes.require_envelope && payload.get("envelope").is_none() {
+        return Err(HttpResponse::BadRequest().body(VerifyError::EnvelopeRequired.to_string()));
+    }
+    if payload.get("signatures").is_some() {
+        let verification = blocking({
+            let data = data.clone();
+            move || {
+                get_field_verification(&payload, data.key.as_ref(), &allowed, data.max_decryptions)
+            }
+        })
+        .await?
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+        return formats.respond(
+            &json!({
+                "verified": verification.verified,
+                "failed": verification.failed,
+                "unsigned": verification.unsigned,
+            }),
+            data.key.public_key(),
+        );
+    }
+    let audience = match req.extensions().get::<Caller>() {
+        Some(caller)
+            if options
+                .audience
+                .as_ref()
+                .is_some_and(|audience| *audience != caller.id) =>
         {
-            "signature": get_signature(
-                &serde_json::from_str(&text).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?,
-                &data.private_key,
+            return Err(HttpResponse::BadRequest().body("audience must be the caller's id"))
+        }
+        Some(caller) => Some(caller.id.clone()),
+        None => options.audience.clone(),
+    };
+    if blocking({
+        let data = data.clone();
+        move || {
+            get_verification(
+                &payload,
+                data.key.as_ref(),
+                &allowed,
+                data.max_decryptions,
+                &data.nonces,
+                audience.as_deref(),
             )
         }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?
+    {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(HttpResponse::BadRequest().finish())
     }
-    .to_string())
 }
 
-pub async fn verify(text: String, data: web::Data<AppState>) -> Result<HttpResponse, HttpResponse> {
-    if get_verification(
-        &serde_json::from_str(&text).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?,
-        &data.public_key,
-        &data.private_key,
-    )
-    .map_err(|e| HttpResponse::BadRequest().body(e))?
+/// Header carrying the base64 signature checked by `/verify/raw`.
+pub const CONTENT_SIGNATURE_HEADER: &str = "X-Content-Signature";
+
+/// SHA256 of an `application/octet-stream` body, hashed as it arrives so it can be of any size.
+async fn hash_raw_body(req: &HttpRequest, mut body: web::Payload) -> Result<Vec<u8>, HttpResponse> {
+    if req.content_type() != "application/octet-stream" {
+        return Err(HttpResponse::UnsupportedMediaType().body("expected application/octet-stream"));
+    }
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.next().await {
+        hasher.update(&chunk.map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Sign arbitrary content, in the same format as `/sign`.
+pub async fn sign_raw(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<String, HttpResponse> {
+    let digest = hash_raw_body(&req, body).await?;
+    let signature = blocking(move || sign_digest(&digest, data.key.as_ref()))
+        .await?
+        .map_err(|e| HttpResponse::InternalServerError().body(e))?;
+    Ok(json!({ "signature": signature }).to_string())
+}
+
+/// Check the `X-Content-Signature` of arbitrary content, answering like `/verify`.
+pub async fn verify_raw(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let signature = req
+        .headers()
+        .get(CONTENT_SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| HttpResponse::BadRequest().body("missing signature"))?
+        .to_string();
+    let digest = hash_raw_body(&req, body).await?;
+    if verify_digest(&digest, &signature, data.key.as_ref())
+        .map_err(|e| HttpResponse::BadRequest().body(e))?
     {
         Ok(HttpResponse::NoContent().finish())
     } else {
@@ -48,11 +607,323 @@ pub async fn verify(text: String, data: web::Data<AppState>) -> Result<HttpRespo
     }
 }
 
-pub async fn config(text: String, data: web::Data<AppState>) -> Result<HttpResponse, HttpResponse> {
-    let mut fields = data.fields_to_encrypt.write().unwrap();
-    let value =
-        serde_json::from_str(&text).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
-    *fields = get_config(&value).map_err(|e| HttpResponse::BadRequest().body(e))?;
-    println!("{:?}", fields);
+/// Run a body through a blob stream and stream out the result. The body is read until the first output or its
+/// end before answering, so a blob with a bad header or key, or a small one that fails anywhere, gets a 400
+/// rather than an interrupted 200.
+async fn stream_blob(
+    req: &HttpRequest,
+    mut body: web::Payload,
+    mut blob: Box<dyn BlobStream + Send>,
+) -> Result<HttpResponse, HttpResponse> {
+    if req.content_type() != "application/octet-stream" {
+        return Err(HttpResponse::UnsupportedMediaType().body("expected application/octet-stream"));
+    }
+    let bad_request = |e: String| HttpResponse::BadRequest().body(e);
+    let mut first = vec![];
+    let mut ended = false;
+    while first.is_empty() && !ended {
+        let chunk = match body.next().await {
+            Some(chunk) => Some(chunk.map_err(|e| bad_request(e.to_string()))?),
+            None => None,
+        };
+        ended = chunk.is_none();
+        // the data key of a blob to decrypt is unwrapped before its first output
+        (blob, first) = blocking(move || {
+            let output = match chunk {
+                Some(chunk) => blob.update(&chunk)?,
+                None => blob.finish()?,
+            };
+            Ok::<_, String>((blob, output))
+        })
+        .await?
+        .map_err(bad_request)?;
+    }
+    let rest = stream::unfold((!ended).then_some((body, blob)), |state| async move {
+        let (mut body, mut blob) = state?;
+        loop {
+            let output = match body.next().await {
+                Some(Ok(chunk)) => blob.update(&chunk),
+                Some(Err(e)) => return Some((Err(e.into()), None)),
+                None => {
+                    return Some((
+                        blob.finish().map(Bytes::from).map_err(ErrorBadRequest),
+                        None,
+                    ))
+                }
+            };
+            match output {
+                Ok(output) if output.is_empty() => continue,
+                Ok(output) => return Some((Ok(Bytes::from(output)), Some((body, blob)))),
+                Err(e) => return Some((Err(ErrorBadRequest(e)), None)),
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .streaming(Box::pin(
+            stream::once(async move { Ok::<_, actix_web::Error>(Bytes::from(first)) }).chain(rest),
+        )))
+}
+
+/// Encrypt any content into a blob, see `field_crypto::blob`.
+pub async fn encrypt_blob(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let encryptor = BlobEncryptor::new(data.key.public_key())
+        .map_err(|e| HttpResponse::InternalServerError().body(e))?;
+    stream_blob(&req, body, Box::new(encryptor)).await
+}
+
+/// Decrypt a blob made by `/encrypt/blob`.
+pub async fn decrypt_blob(
+    req: HttpRequest,
+    body: web::Payload,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let key = data.key.clone();
+    let decryptor = BlobDecryptor::new(move |wrapped: &[u8]| unwrap_raw_key(key.as_ref(), wrapped));
+    stream_blob(&req, body, Box::new(decryptor)).await
+}
+
+/// Migrate the fields encrypted with a retired key to the current key, without the plaintext leaving the
+/// server. Responds with the document and which fields were migrated.
+pub async fn reencrypt(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let payload = formats.decode(&body, data.key.public_key())?;
+    let (migrated_data, migrated, failed) = blocking({
+        let data = data.clone();
+        move || {
+            let retired = data
+                .retired_keys
+                .iter()
+                .map(|(_, key)| key.as_ref())
+                .collect::<Vec<_>>();
+            crate::crypto::reencrypt(&payload, data.key.as_ref(), &retired, data.max_decryptions)
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    record_fields(
+        &req,
+        migrated.iter().map(|field| field.path.clone()).collect(),
+    );
+    formats.respond(
+        &json!({
+            "data": migrated_data,
+            "keyId": data.key_id,
+            "migrated": migrated
+                .iter()
+                .map(|field| json!({ "path": field.path, "fromKeyId": field.from_key_id }))
+                .collect::<Vec<_>>(),
+            "failed": failed,
+        }),
+        data.key.public_key(),
+    )
+}
+
+pub async fn config(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let mut actions = data.field_actions.write().unwrap();
+    let value = formats.decode(&body, data.key.public_key())?;
+    *actions = get_config(&value).map_err(|e| HttpResponse::BadRequest().body(e))?;
+    record_fields(&req, actions.keys().cloned().collect());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The path parameter `name`, percent-decoded from the raw path. `web::Path` keeps `%2F` and `%2B` encoded but
+/// decodes `%25`, so it cannot tell `%2F` sent as is from one sent as `%252F`.
+fn path_param(req: &HttpRequest, name: &str) -> Result<String, HttpResponse> {
+    let placeholder = format!("{{{}}}", name);
+    req.match_pattern()
+        .and_then(|pattern| {
+            pattern
+                .split('/')
+                .position(|segment| segment == placeholder)
+        })
+        .and_then(|index| req.uri().path().split('/').nth(index))
+        .and_then(|segment| percent_decode_str(segment).decode_utf8().ok())
+        .map(|value| value.into_owned())
+        .ok_or_else(|| HttpResponse::BadRequest().body(format!("invalid {} in path", name)))
+}
+
+/// Add or replace the schema named in the path, for `/encrypt?schema=`.
+pub async fn upload_schema(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let name = path_param(&req, "name")?;
+    let formats = Formats::of(&req)?;
+    let schema = Schema::new(formats.decode(&body, data.key.public_key())?)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    data.schemas.write().unwrap().insert(name, Arc::new(schema));
     Ok(HttpResponse::NoContent().finish())
 }
+
+/// Run `operation` on the blocking thread pool rather than on a worker, for the private key operations: an
+/// `AgentKey` waits on its Unix socket for each one, and a `SoftwareKey` does a full RSA operation.
+async fn blocking<T, E>(
+    operation: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<Result<T, E>, HttpResponse>
+where
+    T: Send + 'static,
+    E: Send + Debug + 'static,
+{
+    match web::block(operation).await {
+        Ok(result) => Ok(Ok(result)),
+        Err(BlockingError::Error(e)) => Ok(Err(e)),
+        Err(BlockingError::Canceled) => {
+            Err(HttpResponse::InternalServerError().body("private key operation canceled"))
+        }
+    }
+}
+
+/// Parse a request body, where an empty body is an empty object.
+fn parse_body<T: for<'de> Deserialize<'de>>(
+    formats: &Formats,
+    body: &[u8],
+    public_key: &RsaPublicKey,
+) -> Result<T, HttpResponse> {
+    let value = if body.iter().all(u8::is_ascii_whitespace) {
+        json!({})
+    } else {
+        formats.decode(body, public_key)?
+    };
+    serde_json::from_value(value).map_err(|e| HttpResponse::BadRequest().body(e.to_string()))
+}
+
+fn key_not_found(id: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("no key {}", id))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct WrapRequest {
+    /// Base64 data key.
+    plaintext: String,
+}
+
+pub async fn wrap(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let id = path_param(&req, "id")?;
+    let formats = Formats::of(&req)?;
+    let key = data.key_by_id(&id).ok_or_else(|| key_not_found(&id))?;
+    if key.key_id() != data.key_id {
+        return Err(HttpResponse::BadRequest().body(format!("key {} is retired", key.key_id())));
+    }
+    let request: WrapRequest = parse_body(&formats, &body, data.key.public_key())?;
+    let plaintext = Zeroizing::new(
+        base64::decode(&request.plaintext)
+            .map_err(|_| HttpResponse::BadRequest().body("plaintext must be base64"))?,
+    );
+    formats.respond(
+        &json!({
+            "keyId": data.key_id,
+            "ciphertextBlob": wrap_key(key, &plaintext).map_err(|e| HttpResponse::BadRequest().body(e))?,
+        }),
+        data.key.public_key(),
+    )
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UnwrapRequest {
+    /// Base64 wrapped data key, as returned by `wrap` or `datakey`.
+    ciphertext_blob: String,
+}
+
+pub async fn unwrap(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let id = path_param(&req, "id")?;
+    let formats = Formats::of(&req)?;
+    let key = data.key_by_id(&id).ok_or_else(|| key_not_found(&id))?;
+    let request: UnwrapRequest = parse_body(&formats, &body, key.public_key())?;
+    let key_id = key.key_id();
+    let plaintext = blocking({
+        let data = data.clone();
+        move || match data.key_by_id(&id) {
+            Some(key) => unwrap_key(key, &request.ciphertext_blob),
+            None => Err(format!("no key {}", id)),
+        }
+    })
+    .await?
+    .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    formats.respond(
+        &json!({
+            "keyId": key_id,
+            "plaintext": base64::encode(&*plaintext),
+        }),
+        data.key.public_key(),
+    )
+}
+
+#[derive(Deserialize)]
+pub enum KeySpec {
+    #[serde(rename = "AES_128")]
+    Aes128,
+    #[serde(rename = "AES_256")]
+    Aes256,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DataKeyRequest {
+    /// Size of the key, `AES_256` if neither this nor `numberOfBytes` is given.
+    #[serde(default)]
+    key_spec: Option<KeySpec>,
+    #[serde(default)]
+    number_of_bytes: Option<usize>,
+}
+
+pub async fn datakey(
+    req: HttpRequest,
+    body: Bytes,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, HttpResponse> {
+    let formats = Formats::of(&req)?;
+    let request: DataKeyRequest = parse_body(&formats, &body, data.key.public_key())?;
+    let bytes = match (request.key_spec, request.number_of_bytes) {
+        (Some(_), Some(_)) => {
+            return Err(HttpResponse::BadRequest().body("give either keySpec or numberOfBytes"))
+        }
+        (Some(KeySpec::Aes128), None) => 16,
+        (Some(KeySpec::Aes256), None) | (None, None) => 32,
+        (None, Some(0)) => {
+            return Err(HttpResponse::BadRequest().body("numberOfBytes must be positive"))
+        }
+        (None, Some(bytes)) => bytes,
+    };
+    // checked before the key is made, so a huge size is refused rather than allocated
+    let max = field_crypto::max_data_key_size(data.key.public_key());
+    if bytes > max {
+        return Err(
+            HttpResponse::BadRequest().body(format!("data keys can be at most {} bytes", max))
+        );
+    }
+    let plaintext = generate_data_key(bytes);
+    formats.respond(
+        &json!({
+            "keyId": data.key_id,
+            "plaintext": base64::encode(&*plaintext),
+            "ciphertextBlob": wrap_key(data.key.as_ref(), &plaintext)
+                .map_err(|e| HttpResponse::BadRequest().body(e))?,
+        }),
+        data.key.public_key(),
+    )
+}
@@ -0,0 +1,322 @@
+//! JSON Schemas selecting the fields `/encrypt` encrypts, for documents of a known type.
+//!
+//! A property is encrypted when its schema has `"x-encrypt": true`, or `"x-encrypt": "<class>"` with a
+//! sensitivity class, which a request may narrow down to some classes. Annotations are found through
+//! `properties`, `additionalProperties`, `items`, `allOf` and local `$ref`s, where one beside a `$ref` wins
+//! over its target's; those under `anyOf`, `oneOf` and conditionals are not, as the branch a document takes
+//! is not known. As `/decrypt` only decrypts the values of properties, marked array items are encrypted with
+//! the whole property holding the array.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Schemas a schema may apply to the same value in a row, through `$ref`s and the keywords of `IN_PLACE`.
+const MAX_NESTING: usize = 32;
+
+/// Keywords holding schemas, with whether those apply to the value itself rather than to a part of it, and
+/// whether they are held in a map by name.
+const KEYWORDS: &[(&str, bool, bool)] = &[
+    ("allOf", true, false),
+    ("anyOf", true, false),
+    ("oneOf", true, false),
+    ("not", true, false),
+    ("if", true, false),
+    ("then", true, false),
+    ("else", true, false),
+    ("dependentSchemas", true, true),
+    ("dependencies", true, true),
+    ("properties", false, true),
+    ("patternProperties", false, true),
+    ("$defs", false, true),
+    ("definitions", false, true),
+    ("additionalProperties", false, false),
+    ("unevaluatedProperties", false, false),
+    ("propertyNames", false, false),
+    ("items", false, false),
+    ("prefixItems", false, false),
+    ("additionalItems", false, false),
+    ("unevaluatedItems", false, false),
+    ("contains", false, false),
+];
+
+pub struct Schema {
+    document: Value,
+    validator: jsonschema::Validator,
+}
+
+impl Schema {
+    /// Compile a schema, refusing `x-encrypt` annotations that are neither a boolean nor a class, and schemas
+    /// that apply themselves to the same value again, which would validate forever. References must be JSON
+    /// Pointers within the schema, for the loops to be found.
+    pub fn new(document: Value) -> Result<Self, String> {
+        check_schema(&document, &document, "", &mut HashMap::new())?;
+        let validator =
+            jsonschema::validator_for(&document).map_err(|e| format!("invalid schema: {}", e))?;
+        Ok(Schema {
+            document,
+            validator,
+        })
+    }
+
+    /// Check a document against the schema. The errors name the failing places but not their values, which
+    /// may be what is to be encrypted.
+    pub fn validate(&self, data: &Value) -> Result<(), String> {
+        let errors = self
+            .validator
+            .iter_errors(data)
+            .map(|error| format!("{} fails {}", error.instance_path, error.schema_path))
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(format!(
+            "document does not match schema: {}",
+            errors.join(", ")
+        ))
+    }
+
+    /// JSON Pointers of the values of `data` the schema marks to encrypt, sorted. With `classes`, values
+    /// marked with another class are left out.
+    pub fn encrypted_paths(&self, data: &Value, classes: Option<&[String]>) -> Vec<String> {
+        let mut found = Found::default();
+        self.visit(&self.document, data, "", "", classes, &mut found);
+        let mut paths = found.paths;
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// `property` is the path of the property holding `data`, `path` itself unless in an array.
+    fn visit(
+        &self,
+        schema: &Value,
+        data: &Value,
+        path: &str,
+        property: &str,
+        classes: Option<&[String]>,
+        found: &mut Found,
+    ) {
+        // a schema reached again for the same value, through two `allOf`s, has nothing more to mark
+        if !found
+            .visited
+            .insert((schema as *const Value, path.to_string()))
+        {
+            return;
+        }
+        let selected = match self.keyword(schema, "x-encrypt") {
+            Some(Value::Bool(encrypt)) => *encrypt,
+            Some(Value::String(class)) => classes.is_none_or(|classes| classes.contains(class)),
+            _ => false,
+        };
+        if selected && !property.is_empty() {
+            found.paths.push(property.to_string());
+            return;
+        }
+        for part in self
+            .refs(schema)
+            .filter_map(|schema| schema.get("allOf").and_then(Value::as_array))
+            .flatten()
+        {
+            self.visit(part, data, path, property, classes, found);
+        }
+        match data {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let property = self
+                        .refs(schema)
+                        .find_map(|schema| schema.get("properties")?.get(key))
+                        .or_else(|| self.keyword(schema, "additionalProperties"));
+                    if let Some(property) = property {
+                        let path = field_crypto::pointer_push(path, key);
+                        self.visit(property, value, &path, &path, classes, found);
+                    }
+                }
+            }
+            Value::Array(vec) => {
+                if let Some(items) = self.keyword(schema, "items") {
+                    for (index, value) in vec.iter().enumerate() {
+                        let path = field_crypto::pointer_push(path, &index.to_string());
+                        self.visit(items, value, &path, property, classes, found);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// `schema` and the schemas its `$ref`s within the schema document lead to, in order. Other references
+    /// are not followed.
+    fn refs<'a>(&'a self, schema: &'a Value) -> impl Iterator<Item = &'a Value> {
+        std::iter::successors(Some(schema), |schema| {
+            schema
+                .get("$ref")
+                .and_then(Value::as_str)
+                .and_then(|reference| reference.strip_prefix('#'))
+                .and_then(|pointer| self.document.pointer(pointer))
+        })
+        .take(MAX_NESTING + 1)
+    }
+
+    /// `keyword` of `schema`, or else of the nearest schema its `$ref`s lead to, so that one written beside a
+    /// `$ref` is not lost.
+    fn keyword<'a>(&'a self, schema: &'a Value, keyword: &str) -> Option<&'a Value> {
+        self.refs(schema).find_map(|schema| schema.get(keyword))
+    }
+}
+
+/// The paths `Schema::visit` has marked, and the schemas it has applied to each path.
+#[derive(Default)]
+struct Found {
+    paths: Vec<String>,
+    visited: HashSet<(*const Value, String)>,
+}
+
+/// The schemas `keyword` of `schema` holds, with their paths below `schema`.
+fn held<'a>(schema: &'a Value, keyword: &str, map: bool) -> Vec<(String, &'a Value)> {
+    let path = field_crypto::pointer_push("", keyword);
+    let held = match schema.get(keyword) {
+        Some(Value::Object(schemas)) if map => schemas
+            .iter()
+            .map(|(name, schema)| (field_crypto::pointer_push(&path, name), schema))
+            .collect(),
+        Some(Value::Array(schemas)) if !map => schemas
+            .iter()
+            .enumerate()
+            .map(|(index, schema)| {
+                (
+                    field_crypto::pointer_push(&path, &index.to_string()),
+                    schema,
+                )
+            })
+            .collect(),
+        Some(schema) if !map => vec![(path, schema)],
+        _ => vec![],
+    };
+    held.into_iter()
+        .filter(|(_, schema)| schema.is_object() || schema.is_boolean())
+        .collect()
+}
+
+/// Refuse `x-encrypt` annotations of the wrong type, and schemas applying to the same value in a loop or more
+/// than `MAX_NESTING` times in a row, anywhere in `schema` of `document`.
+fn check_schema(
+    document: &Value,
+    schema: &Value,
+    path: &str,
+    nestings: &mut HashMap<*const Value, usize>,
+) -> Result<(), String> {
+    if let Some(annotation) = schema.get("x-encrypt") {
+        if !annotation.is_boolean() && !annotation.is_string() {
+            return Err(format!(
+                "x-encrypt must be a boolean or a class at {}",
+                field_crypto::pointer_push(path, "x-encrypt")
+            ));
+        }
+    }
+    nesting(document, schema, &mut vec![], nestings).map_err(|e| match path {
+        "" => e,
+        path => format!("{} at {}", e, path),
+    })?;
+    for (keyword, _, map) in KEYWORDS {
+        for (below, held) in held(schema, keyword, *map) {
+            check_schema(document, held, &format!("{}{}", path, below), nestings)?;
+        }
+    }
+    Ok(())
+}
+
+/// How many schemas apply to the same value in a row from `schema` on, itself included. `chain` holds the
+/// schemas applied before it, and `nestings` those already counted.
+fn nesting(
+    document: &Value,
+    schema: &Value,
+    chain: &mut Vec<*const Value>,
+    nestings: &mut HashMap<*const Value, usize>,
+) -> Result<usize, String> {
+    let too_many = || {
+        format!(
+            "schema applies more than {} schemas to the same value in a row",
+            MAX_NESTING
+        )
+    };
+    let address = schema as *const Value;
+    if let Some(nesting) = nestings.get(&address) {
+        if chain.len() + nesting > MAX_NESTING {
+            return Err(too_many());
+        }
+        return Ok(*nesting);
+    }
+    if chain.contains(&address) {
+        return Err("schema applies itself to the same value again".to_string());
+    }
+    if chain.len() >= MAX_NESTING {
+        return Err(too_many());
+    }
+    // both change what a reference leads to, the loops through them would go unnoticed
+    for keyword in ["$dynamicRef", "$recursiveRef"] {
+        if schema.get(keyword).is_some() {
+            return Err(format!("{} is not supported", keyword));
+        }
+    }
+    if !std::ptr::eq(schema, document)
+        && schema
+            .get("$id")
+            .or_else(|| schema.get("id"))
+            .is_some_and(Value::is_string)
+    {
+        return Err("$id is only supported at the top of the schema".to_string());
+    }
+    let mut applied = vec![];
+    if let Some(reference) = schema.get("$ref") {
+        let target = reference
+            .as_str()
+            .filter(|reference| !reference.contains('%'))
+            .and_then(|reference| reference.strip_prefix('#'))
+            .and_then(|pointer| document.pointer(pointer))
+            .ok_or("$ref must be a JSON Pointer within the schema")?;
+        applied.push(target);
+    }
+    for (keyword, in_place, map) in KEYWORDS {
+        if *in_place {
+            applied.extend(
+                held(schema, keyword, *map)
+                    .into_iter()
+                    .map(|(_, held)| held),
+            );
+        }
+    }
+    chain.push(address);
+    let mut most = 0;
+    for applied in applied {
+        most = most.max(nesting(document, applied, chain, nestings)?);
+    }
+    chain.pop();
+    nestings.insert(address, most + 1);
+    Ok(most + 1)
+}
+
+/// Load the `.json` files of `directory` as schemas named after the files, `order.json` as `order`.
+pub fn load_directory(directory: &Path) -> Result<BTreeMap<String, Schema>, String> {
+    let entries = fs::read_dir(directory)
+        .map_err(|e| format!("failed to read {}: {}", directory.display(), e))?;
+    let mut schemas = BTreeMap::new();
+    for entry in entries {
+        let file = entry.map_err(|e| e.to_string())?.path();
+        if file.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let name = file
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("invalid schema file name {}", file.display()))?;
+        let schema = fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+            .and_then(Schema::new)
+            .map_err(|e| format!("{}: {}", file.display(), e))?;
+        schemas.insert(name.to_string(), schema);
+    }
+    Ok(schemas)
+}
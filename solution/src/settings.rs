@@ -0,0 +1,76 @@
+use crate::envelope::EnvelopeSettings;
+use crate::limits::LimitSettings;
+use crate::provider::KeySettings;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Server settings, read from a JSON file at startup. Every field is optional.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Settings {
+    /// Where the key pair is kept, the `pub_key` and `priv_key` files by default.
+    pub key: KeySettings,
+    /// Previous keys, still used to unwrap data keys and by `/reencrypt` to migrate fields to the current key.
+    pub retired_keys: Vec<KeySettings>,
+    /// File with the credentials accepted by the server (see `auth::load_credentials`).
+    /// Authentication is disabled when not set.
+    pub credentials_file: Option<PathBuf>,
+    /// File with the roles given to callers in the credentials file (see `policy::Policy::load`).
+    /// Only permissions given directly in the credentials apply when not set.
+    pub policy_file: Option<PathBuf>,
+    /// Append a hash chained JSON line for every request to this file (see `audit::AuditLog`).
+    pub audit_log_file: Option<PathBuf>,
+    /// Directory of the token vault of `/tokenize` (see `vault::TokenVault`). Without it `/tokenize` and
+    /// `/detokenize` are refused and `/encrypt` makes keyed hash tokens.
+    pub token_vault_directory: Option<PathBuf>,
+    /// Directory of JSON Schemas for `/encrypt?schema=`, named after their files (see `schema::Schema`).
+    pub schema_directory: Option<PathBuf>,
+    /// What `/encrypt` does with personal data it would leave in clear (see `field_crypto::pii`), nothing when
+    /// not set.
+    pub pii_detection: Option<PiiDetection>,
+    /// Rate, concurrency and decryption limits of the routes using the private key (see `limits::RateLimit`).
+    pub limits: LimitSettings,
+    /// Lifetimes and replay protection of signed envelopes (see `envelope::Envelope`).
+    pub envelopes: EnvelopeSettings,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsSettings>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PiiDetection {
+    /// Name the fields in the `X-Pii-Detected` response header.
+    Report,
+    /// Encrypt the fields, and name them in the header too.
+    Encrypt,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM certificate chain, reloaded when the file changes.
+    pub certificate_file: PathBuf,
+    /// PEM private key (PKCS#8 or PKCS#1) of the certificate, reloaded when the file changes.
+    pub private_key_file: PathBuf,
+    /// PEM CA certificates that client certificates are verified against. Client certificates are not
+    /// requested when not set.
+    #[serde(default)]
+    pub client_ca_file: Option<PathBuf>,
+    /// Refuse connections without a valid client certificate.
+    #[serde(default)]
+    pub require_client_certificate: bool,
+}
+
+impl Settings {
+    /// Read the settings file, or use the defaults if it does not exist.
+    pub fn load<T: AsRef<Path>>(file: T) -> Result<Settings, String> {
+        match fs::read_to_string(&file) {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| format!("invalid settings in {}: {}", file.as_ref().display(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Settings::default()),
+            Err(e) => Err(format!("failed to read {}: {}", file.as_ref().display(), e)),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! TLS listener configuration. The server certificate is reloaded from disk when its files change, so
+//! certificates can be rotated without a restart. Client certificates, when requested, are verified against
+//! a CA file and their subject is made available to the `auth::ClientCertificateAuthenticator`.
+
+use crate::settings::TlsSettings;
+use actix_http::body::MessageBody;
+use actix_http::{Error, HttpService, Request, Response};
+use actix_server::Server;
+use actix_service::{map_config, IntoServiceFactory, Service, ServiceFactory};
+use actix_tls::rustls::TlsStream;
+use actix_web::dev::AppConfig;
+use actix_web::rt::net::TcpStream;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, NoClientAuth,
+    ResolvesServerCert, RootCertStore, ServerConfig, Session,
+};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Subject of the verified client certificate of the connection, e.g. `C=US, O=Acme, CN=producer`, `None`
+/// without one.
+#[derive(Clone, Debug)]
+pub struct PeerSubject(pub Option<String>);
+
+fn open<T: AsRef<Path>>(file: T) -> Result<BufReader<File>, String> {
+    File::open(&file)
+        .map(BufReader::new)
+        .map_err(|e| format!("failed to read {}: {}", file.as_ref().display(), e))
+}
+
+/// Read a PEM certificate chain and its PKCS#8 or PKCS#1 private key.
+fn load_certified_key(
+    certificate_file: &Path,
+    private_key_file: &Path,
+) -> Result<CertifiedKey, String> {
+    let chain = certs(&mut open(certificate_file)?)
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .ok_or_else(|| format!("no certificate in {}", certificate_file.display()))?;
+    let key = pkcs8_private_keys(&mut open(private_key_file)?)
+        .ok()
+        .and_then(|mut keys| keys.pop())
+        .or_else(|| {
+            rsa_private_keys(&mut open(private_key_file).ok()?)
+                .ok()
+                .and_then(|mut keys| keys.pop())
+        })
+        .ok_or_else(|| format!("no private key in {}", private_key_file.display()))?;
+    let key = any_supported_type(&key)
+        .map_err(|_| format!("unsupported private key in {}", private_key_file.display()))?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|meta| meta.modified()).ok()
+}
+
+/// Modification times of the certificate and private key files.
+type Stamps = (Option<SystemTime>, Option<SystemTime>);
+
+/// Serves the certificate from its files, reloading them on the next handshake after either file changes.
+struct ReloadingResolver {
+    certificate_file: PathBuf,
+    private_key_file: PathBuf,
+    /// Modification times of the loaded files, and the key loaded from them.
+    current: RwLock<(Stamps, CertifiedKey)>,
+}
+
+impl ReloadingResolver {
+    fn new(certificate_file: PathBuf, private_key_file: PathBuf) -> Result<Self, String> {
+        let stamps = (modified(&certificate_file), modified(&private_key_file));
+        let key = load_certified_key(&certificate_file, &private_key_file)?;
+        Ok(ReloadingResolver {
+            certificate_file,
+            private_key_file,
+            current: RwLock::new((stamps, key)),
+        })
+    }
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        let stamps = (
+            modified(&self.certificate_file),
+            modified(&self.private_key_file),
+        );
+        if self.current.read().unwrap().0 != stamps {
+            let mut current = self.current.write().unwrap();
+            // a half written pair fails to load, keep serving the old one until the next change
+            match load_certified_key(&self.certificate_file, &self.private_key_file) {
+                Ok(key) => {
+                    println!("Reloaded TLS certificate");
+                    *current = (stamps, key);
+                }
+                Err(e) => {
+                    eprintln!("Failed to reload TLS certificate: {}", e);
+                    current.0 = stamps;
+                }
+            }
+        }
+        Some(self.current.read().unwrap().1.clone())
+    }
+}
+
+/// Build the rustls configuration for the listener.
+pub fn server_config(settings: &TlsSettings) -> Result<ServerConfig, String> {
+    let verifier = match &settings.client_ca_file {
+        Some(file) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(file)?) {
+                Ok((added, _)) if added > 0 => {}
+                _ => return Err(format!("no CA certificate in {}", file.display())),
+            }
+            if settings.require_client_certificate {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        None if settings.require_client_certificate => {
+            return Err("requireClientCertificate needs a clientCaFile".to_string())
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.cert_resolver = Arc::new(ReloadingResolver::new(
+        settings.certificate_file.clone(),
+        settings.private_key_file.clone(),
+    )?);
+    Ok(config)
+}
+
+/// Serve the application `factory` makes over HTTPS on `address`. Unlike `HttpServer::on_connect`, which
+/// hands its data to the first request of a connection only, every request gets the `PeerSubject` of its
+/// connection, also on a kept alive HTTP/1.1 connection or as an HTTP/2 stream.
+pub fn serve<F, I, S, B>(
+    factory: F,
+    config: ServerConfig,
+    address: (&str, u16),
+) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let listener = TcpListener::bind(address)?;
+    Ok(Server::build()
+        .listen("https", listener, move || {
+            HttpService::build()
+                .on_connect(peer_subject)
+                .finish(map_config(factory(), |_| AppConfig::default()))
+                .rustls(config.clone())
+        })?
+        .run())
+}
+
+/// The subject of the verified client certificate of a connection.
+fn peer_subject(stream: &TlsStream<TcpStream>) -> PeerSubject {
+    PeerSubject(
+        stream
+            .get_ref()
+            .1
+            .get_peer_certificates()
+            .and_then(|chain| {
+                let (_, certificate) =
+                    x509_parser::parse_x509_certificate(&chain.first()?.0).ok()?;
+                Some(certificate.subject().to_string())
+            }),
+    )
+}
@@ -0,0 +1,191 @@
+//! The token vault: format-preserving tokens for field values, kept with the values they stand for, which are
+//! encrypted with the server's key.
+//!
+//! A token keeps the length of the value's text and its other characters, but every digit is a random digit,
+//! every upper case letter a random upper case letter and any other letter a random lower case letter, so a
+//! card number stays sixteen digits for the schemas that check it. A value always gets the same token, found
+//! by a keyed hash of the value. The key of that hash is random and kept in the vault wrapped with the
+//! server's key, so values are still found after the key is rotated. The vault is a sled database directory.
+
+use crate::crypto::{
+    decrypt_value, generate_data_key, keyed_hash, tokenization_key, unwrap_key, wrap_key,
+};
+use crate::provider::KeyProvider;
+use field_crypto::actions::text;
+use field_crypto::encrypt_pub_string;
+use rand::rngs::OsRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Random tokens tried for a value before giving up, as a value with few letters or digits has few tokens.
+const MAX_TOKEN_ATTEMPTS: usize = 32;
+
+/// Bytes of the key values are looked up with.
+const LOOKUP_KEY_SIZE: usize = 32;
+
+/// Where the lookup key is in the `settings` tree.
+const LOOKUP_KEY: &[u8] = b"lookupKey";
+
+/// A value in the vault, or its lookup key.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Entry {
+    /// Id of the key the value is encrypted with.
+    key_id: String,
+    /// The value's JSON, encrypted like a field, or the lookup key wrapped by `wrap_key`.
+    ciphertext: String,
+}
+
+pub struct TokenVault {
+    /// Token to `Entry`.
+    tokens: sled::Tree,
+    /// Keyed hash of a value to its token.
+    values: sled::Tree,
+    lookup_key: Zeroizing<Vec<u8>>,
+}
+
+impl TokenVault {
+    /// Open the vault in `directory`, creating it if needed. Its lookup key is wrapped with `key`, the current
+    /// key, and unwrapped with the key `key_by_id` finds for it.
+    pub fn open<'a>(
+        directory: &Path,
+        key: &dyn KeyProvider,
+        key_by_id: &dyn Fn(&str) -> Option<&'a dyn KeyProvider>,
+    ) -> Result<Self, String> {
+        let error = |e: sled::Error| format!("token vault {}: {}", directory.display(), e);
+        let db = sled::open(directory).map_err(error)?;
+        let values = db.open_tree("values").map_err(error)?;
+        let settings = db.open_tree("settings").map_err(error)?;
+        let (lookup_key, wrapped_with) = match settings.get(LOOKUP_KEY).map_err(error)? {
+            Some(entry) => {
+                let entry: Entry = serde_json::from_slice(&entry).map_err(|e| e.to_string())?;
+                let wrapped_with = key_by_id(&entry.key_id)
+                    .ok_or_else(|| format!("token vault key {} is not loaded", entry.key_id))?;
+                (
+                    unwrap_key(wrapped_with, &entry.ciphertext)?,
+                    Some(entry.key_id),
+                )
+            }
+            // vaults made before the lookup key was kept looked values up with `tokenization_key` of their
+            // key. HMAC first hashes keys that long, so its SHA256 finds them the same.
+            None if !values.is_empty() => (
+                Zeroizing::new(Sha256::digest(tokenization_key(key)?.as_slice()).to_vec()),
+                None,
+            ),
+            None => (generate_data_key(LOOKUP_KEY_SIZE), None),
+        };
+        // wrapped again with the current key, so the vault still opens once a retired key is unloaded
+        if wrapped_with != Some(key.key_id()) {
+            settings
+                .insert(LOOKUP_KEY, wrapped_lookup_key(&lookup_key, key)?)
+                .map_err(error)?;
+            db.flush().map_err(error)?;
+        }
+        Ok(TokenVault {
+            tokens: db.open_tree("tokens").map_err(error)?,
+            values,
+            lookup_key,
+        })
+    }
+
+    /// The token of `value`, stored encrypted with `key` the first time it is tokenized. A value without
+    /// letters or digits is its own token.
+    pub fn tokenize(&self, value: &Value, key: &dyn KeyProvider) -> Result<String, String> {
+        let text = text(value);
+        if !text.chars().any(char::is_alphanumeric) {
+            return Ok(text.to_string());
+        }
+        let plaintext = Zeroizing::new(value.to_string());
+        let lookup = keyed_hash(&self.lookup_key, plaintext.as_bytes())?;
+        if let Some(token) = self.values.get(&lookup).map_err(vault_error)? {
+            return token_string(&token);
+        }
+        let entry = serde_json::to_vec(&Entry {
+            key_id: key.key_id(),
+            ciphertext: encrypt_pub_string(key.public_key(), &plaintext)?,
+        })
+        .map_err(|e| e.to_string())?;
+        for _ in 0..MAX_TOKEN_ATTEMPTS {
+            let token = random_token(&text);
+            if token == *text {
+                continue;
+            }
+            // claim the token first, then the value, which a concurrent request may have tokenized meanwhile
+            if self
+                .tokens
+                .compare_and_swap(
+                    token.as_bytes(),
+                    None as Option<&[u8]>,
+                    Some(entry.as_slice()),
+                )
+                .map_err(vault_error)?
+                .is_err()
+            {
+                continue;
+            }
+            return match self
+                .values
+                .compare_and_swap(&lookup, None as Option<&[u8]>, Some(token.as_bytes()))
+                .map_err(vault_error)?
+            {
+                Ok(()) => Ok(token),
+                Err(conflict) => {
+                    self.tokens.remove(token.as_bytes()).map_err(vault_error)?;
+                    token_string(&conflict.current.unwrap_or_default())
+                }
+            };
+        }
+        Err("no free token for a value of this format".to_string())
+    }
+
+    /// The value `token` stands for, if it is in the vault. `key_by_id` finds the key it is encrypted with.
+    pub fn detokenize<'a>(
+        &self,
+        token: &str,
+        key_by_id: &dyn Fn(&str) -> Option<&'a dyn KeyProvider>,
+    ) -> Result<Option<Value>, String> {
+        let entry = match self.tokens.get(token.as_bytes()).map_err(vault_error)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let entry: Entry = serde_json::from_slice(&entry).map_err(|e| e.to_string())?;
+        let key = key_by_id(&entry.key_id)
+            .ok_or_else(|| format!("token vault key {} is not loaded", entry.key_id))?;
+        decrypt_value(key, &entry.ciphertext)
+            .map(Some)
+            .ok_or_else(|| "failed to decrypt a token vault entry".to_string())
+    }
+}
+
+/// The `Entry` of the lookup key, wrapped with `key`.
+fn wrapped_lookup_key(lookup_key: &[u8], key: &dyn KeyProvider) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(&Entry {
+        key_id: key.key_id(),
+        ciphertext: wrap_key(key, lookup_key)?,
+    })
+    .map_err(|e| e.to_string())
+}
+
+fn vault_error(e: sled::Error) -> String {
+    format!("token vault: {}", e)
+}
+
+fn token_string(token: &[u8]) -> Result<String, String> {
+    String::from_utf8(token.to_vec()).map_err(|e| e.to_string())
+}
+
+/// A random token of the same format as `text`.
+fn random_token(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            c if c.is_ascii_digit() => OsRng.gen_range('0'..='9'),
+            c if c.is_uppercase() => OsRng.gen_range('A'..='Z'),
+            c if c.is_alphanumeric() => OsRng.gen_range('a'..='z'),
+            c => c,
+        })
+        .collect()
+}
@@ -1,28 +1,33 @@
+use client::{Client, ClientBuilder};
+use field_crypto::format::Format;
+use field_crypto::rsa::RsaPublicKey;
+use interview_problem::crypto;
+#[cfg(unix)]
+use interview_problem::provider::{agent, AgentKey};
+use interview_problem::provider::{KeyProvider, SoftwareKey};
 use serde_json::{json, Value};
+#[cfg(unix)]
+use std::sync::Arc;
+
+/// Errors if
+/// * the configuration is rejected
+pub async fn test_config(client: &Client, fields: &[String]) -> Result<(), String> {
+    client
+        .set_config(fields)
+        .await
+        .map_err(|e| format!("Failed to set configuration: {}", e))
+}
 
 /// Errors if
 /// * no body is received
 /// * the body is not a valid JSON
 /// * the body as JSON is not an Object on the first level
 /// * all first level values for the Object are not Strings
-pub async fn test_encrypt(
-    base_url: &str,
-    client: &reqwest::Client,
-    base_data: &Value,
-) -> Result<Value, String> {
-    let url = format!("{}/encrypt", base_url);
-    let response = client
-        .post(&url)
-        .body(base_data.to_string())
-        .send()
-        .await
-        .map_err(|_| "Failed to send request")?;
-    let body = response
-        .text()
-        .await
-        .map_err(|_| "Failed to receive response with body from encryption")?;
-    let value: Value =
-        serde_json::from_str(&body).map_err(|_| "Malformed JSON response in encryption")?;
+pub async fn test_encrypt(client: &Client, base_data: &Value) -> Result<Value, String> {
+    let value = client
+        .encrypt(base_data)
+        .await
+        .map_err(|e| format!("Failed encryption: {}", e))?;
     if value
         .as_object()
         .ok_or("JSON response is not Object in encryption")?
@@ -40,24 +45,14 @@ pub async fn test_encrypt(
 /// * the body is not a valid JSON
 /// * the body as JSON is not the same as the base_data
 pub async fn test_decrypt(
-    base_url: &str,
-    client: &reqwest::Client,
+    client: &Client,
     encrypted_data: &Value,
     base_data: &Value,
 ) -> Result<(), String> {
-    let url = format!("{}/decrypt", base_url);
-    let response = client
-        .post(&url)
-        .body(encrypted_data.to_string())
-        .send()
-        .await
-        .unwrap();
-    let body = response
-        .text()
-        .await
-        .map_err(|_| "Failed to receive response with body from decryption")?;
-    let value: Value =
-        serde_json::from_str(&body).map_err(|_| "Malformed JSON response in decryption")?;
+    let value = client
+        .decrypt(encrypted_data)
+        .await
+        .map_err(|e| format!("Failed decryption: {}", e))?;
     if value == *base_data {
         Ok(())
     } else {
@@ -65,62 +60,763 @@ pub async fn test_decrypt(
     }
 }
 
-/// Panics if
+/// Errors if
 /// * no body is received
 /// * the body is not a valid JSON
 /// * the body as JSON is missing the `signature` field
 /// * the `signature` field is not a string
-pub async fn test_signature(
-    base_url: &str,
-    client: &reqwest::Client,
-    base_data: &Value,
-) -> Result<String, String> {
-    let url = format!("{}/sign", base_url);
-    let response = client
-        .post(&url)
-        .body(base_data.to_string())
-        .send()
-        .await
-        .map_err(|_| "Failed to receive response.")?;
-    let body = response
-        .text()
-        .await
-        .map_err(|_| "Failed to receive response with body from signature")?;
-    Ok(serde_json::from_str::<Value>(&body)
-        .expect("Malformed JSON response in signature")
-        .get("signature")
-        .ok_or("Missing signature field on JSON response in signature")?
-        .as_str()
-        .ok_or("Signature field is not a string in signature")?
-        .to_string())
+pub async fn test_signature(client: &Client, base_data: &Value) -> Result<String, String> {
+    client
+        .sign(base_data)
+        .await
+        .map_err(|e| format!("Failed signature: {}", e))
 }
 
 /// Errors if
 /// * response is not received
 /// * response status is not 204 No Content
 pub async fn test_verification(
-    base_url: &str,
-    client: &reqwest::Client,
+    client: &Client,
     encrypted_data: &Value,
     signature: &str,
 ) -> Result<(), String> {
-    let url = format!("{}/verify", base_url);
-    let response = client
-        .post(&url)
-        .body(
-            json! ({
-                "data": encrypted_data,
-                "signature": signature,
-            })
-            .to_string(),
-        )
-        .send()
-        .await
-        .map_err(|_| "Failed to receive response.")?;
+    match client.verify(encrypted_data, signature).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("Verification failed".to_string()),
+        Err(e) => Err(format!("Failed verification: {}", e)),
+    }
+}
+
+/// Encrypts every first level field locally with the published public key and sends the result for decryption.
+/// Errors if
+/// * local encryption fails
+/// * the decrypted response is not the same as the base_data, also with the fields encrypted as they were
+///   before fields were bound to their paths
+pub async fn test_local_encrypt(
+    client: &Client,
+    public_key: &RsaPublicKey,
+    base_data: &Value,
+) -> Result<(), String> {
+    let map = base_data
+        .as_object()
+        .ok_or("Base data is not an Object in local encryption")?;
+    let fields = map.keys().cloned().collect::<Vec<_>>();
+    let encrypted = field_crypto::detect_and_encrypt(base_data, public_key, &fields)?;
+    test_decrypt(client, &encrypted, base_data).await?;
+    let unbound = map
+        .iter()
+        .map(|(key, value)| {
+            let ciphertext = field_crypto::encrypt_pub_string(public_key, &value.to_string())?;
+            Ok((key.clone(), json!(ciphertext)))
+        })
+        .collect::<Result<serde_json::Map<_, _>, String>>()?;
+    test_decrypt(client, &Value::Object(unbound), base_data).await
+}
 
-    if response.status() == 204 {
+/// Errors if
+/// * the signature from the server does not verify locally against the plaintext with the public key
+pub fn test_local_verification(
+    public_key: &RsaPublicKey,
+    base_data: &Value,
+    signature: &str,
+) -> Result<(), String> {
+    if field_crypto::verify_signature(base_data, signature, public_key) {
         Ok(())
     } else {
-        Err("Verification failed".to_string())
+        Err("Local verification failed".to_string())
+    }
+}
+
+/// Errors if
+/// * a generated data key is not wrapped by the server's key
+/// * a generated or a locally chosen data key does not unwrap to the same bytes
+pub async fn test_key_wrapping(client: &Client, public_key: &RsaPublicKey) -> Result<(), String> {
+    let data_key = client
+        .data_key(32)
+        .await
+        .map_err(|e| format!("Failed to generate a data key: {}", e))?;
+    let key_id = field_crypto::fingerprint(public_key);
+    if data_key.key_id != key_id || data_key.plaintext.len() != 32 {
+        return Err("Generated data key does not match the request".to_string());
+    }
+    let own_key = [7; 24];
+    let wrapped = client
+        .wrap("current", &own_key)
+        .await
+        .map_err(|e| format!("Failed to wrap a data key: {}", e))?;
+    for (plaintext, wrapped) in [
+        (&data_key.plaintext[..], &data_key.wrapped),
+        (&own_key[..], &wrapped),
+    ] {
+        let unwrapped = client
+            .unwrap(&key_id, wrapped)
+            .await
+            .map_err(|e| format!("Failed to unwrap a data key: {}", e))?;
+        if unwrapped != plaintext {
+            return Err("Unwrapped data key does not match".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * re-encryption fails
+/// * a field already encrypted with the current key is migrated or changed
+pub async fn test_reencrypt(client: &Client, encrypted: &Value) -> Result<(), String> {
+    let report = client
+        .reencrypt(encrypted)
+        .await
+        .map_err(|e| format!("Failed re-encryption: {}", e))?;
+    if report["migrated"] != json!([]) || report["failed"] != json!([]) {
+        return Err(format!("Unexpected re-encryption report: {}", report));
+    }
+    if &report["data"] != encrypted {
+        return Err("Re-encryption changed fields of the current key".to_string());
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * the field signatures cannot be made or checked
+/// * a field of the encrypted document does not verify
+/// * after removing a field, it does not fail or another field does not verify
+pub async fn test_field_signatures(
+    client: &Client,
+    base_data: &Value,
+    encrypted: &Value,
+) -> Result<(), String> {
+    let signatures = client
+        .sign_fields(base_data)
+        .await
+        .map_err(|e| format!("Failed field signing: {}", e))?;
+    let signed = signatures
+        .as_object()
+        .ok_or("Field signatures are not an Object")?
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    let report = client
+        .verify_fields(encrypted, &signatures)
+        .await
+        .map_err(|e| format!("Failed field verification: {}", e))?;
+    if report["verified"] != json!(signed) || report["failed"] != json!([]) {
+        return Err(format!("Unexpected field verification: {}", report));
+    }
+    let mut redacted = encrypted.clone();
+    let removed = match redacted.as_object_mut().and_then(|map| {
+        let key = map.keys().next()?.clone();
+        map.remove(&key).map(|_| key)
+    }) {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    let report = client
+        .verify_fields(&redacted, &signatures)
+        .await
+        .map_err(|e| format!("Failed field verification: {}", e))?;
+    let prefix = field_crypto::pointer_push("", &removed);
+    let expected_failed = signed
+        .iter()
+        .filter(|path| *path == &prefix || path.starts_with(&format!("{}/", prefix)))
+        .collect::<Vec<_>>();
+    if report["failed"] != json!(expected_failed)
+        || report["verified"].as_array().map(Vec::len) != Some(signed.len() - expected_failed.len())
+    {
+        return Err(format!(
+            "Unexpected field verification after redaction: {}",
+            report
+        ));
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * an envelope cannot be signed
+/// * the envelope does not verify once for `audience`, the client's caller id with authentication
+/// * the envelope verifies a second time, or for another audience
+pub async fn test_envelope(
+    client: &Client,
+    base_data: &Value,
+    encrypted: &Value,
+    audience: &str,
+) -> Result<(), String> {
+    let sign = || async {
+        client
+            .sign_envelope(base_data, Some(60), Some(audience))
+            .await
+            .map_err(|e| format!("Failed envelope signing: {}", e))
+    };
+    let signed = sign().await?;
+    if !client
+        .verify_envelope(encrypted, &signed, Some(audience))
+        .await
+        .map_err(|e| format!("Failed envelope verification: {}", e))?
+    {
+        return Err("Envelope signature does not match".to_string());
+    }
+    if client
+        .verify_envelope(encrypted, &signed, Some(audience))
+        .await
+        .is_ok()
+    {
+        return Err("Envelope verified twice".to_string());
+    }
+    if client
+        .verify_envelope(encrypted, &sign().await?, Some("other"))
+        .await
+        .is_ok()
+    {
+        return Err("Envelope verified for another audience".to_string());
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * content larger than the JSON body limit cannot be signed
+/// * its signature does not verify, or verifies other content
+pub async fn test_raw_signature(client: &Client) -> Result<(), String> {
+    let content = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let signature = client
+        .sign_raw(&content)
+        .await
+        .map_err(|e| format!("Failed raw signing: {}", e))?;
+    if !client
+        .verify_raw(&content, &signature)
+        .await
+        .map_err(|e| format!("Failed raw verification: {}", e))?
+    {
+        return Err("Raw signature does not match".to_string());
+    }
+    if client
+        .verify_raw(&content[1..], &signature)
+        .await
+        .map_err(|e| format!("Failed raw verification: {}", e))?
+    {
+        return Err("Raw signature matches other content".to_string());
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * content spanning several chunks, or none, does not decrypt back from its blob
+/// * a blob with a changed byte decrypts
+pub async fn test_blob(client: &Client) -> Result<(), String> {
+    let content = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for content in [&content[..], &[]] {
+        let blob = client
+            .encrypt_blob(content)
+            .await
+            .map_err(|e| format!("Failed blob encryption: {}", e))?;
+        let decrypted = client
+            .decrypt_blob(&blob)
+            .await
+            .map_err(|e| format!("Failed blob decryption: {}", e))?;
+        if decrypted != content {
+            return Err("Blob does not decrypt to its content".to_string());
+        }
+    }
+    let mut blob = client
+        .encrypt_blob(b"attachment")
+        .await
+        .map_err(|e| format!("Failed blob encryption: {}", e))?;
+    let last = blob.len() - 1;
+    blob[last] ^= 1;
+    if client.decrypt_blob(&blob).await.is_ok() {
+        return Err("Tampered blob decrypted".to_string());
+    }
+    Ok(())
+}
+
+/// Errors if, for CBOR and MessagePack
+/// * the encrypted document is not in the same format, or a configured field is not encrypted
+/// * a ciphertext is not a byte string
+/// * the document does not decrypt back to `data`
+pub async fn test_formats(
+    client: &Client,
+    public_key: &RsaPublicKey,
+    data: &Value,
+) -> Result<(), String> {
+    for format in [Format::Cbor, Format::MessagePack] {
+        let encoded = format.encode(data, public_key)?;
+        let encrypted = client
+            .encrypt_as(format, &encoded)
+            .await
+            .map_err(|e| format!("Failed {:?} encryption: {}", format, e))?;
+        let document = format.decode(&encrypted, public_key)?;
+        for (key, value) in data.as_object().ok_or("Expected an object")? {
+            let ciphertext = document[key]
+                .as_str()
+                .filter(|value| field_crypto::looks_encrypted(value, public_key))
+                .ok_or_else(|| format!("{:?} field {} is not encrypted: {}", format, key, value))?;
+            let bytes = field_crypto::decode_ciphertext(ciphertext).unwrap();
+            if !encrypted.windows(bytes.len()).any(|window| window == bytes) {
+                return Err(format!("{:?} field {} is not a byte string", format, key));
+            }
+        }
+        let decrypted = client
+            .decrypt_as(format, &encrypted)
+            .await
+            .map_err(|e| format!("Failed {:?} decryption: {}", format, e))?;
+        if &format.decode(&decrypted, public_key)? != data {
+            return Err(format!("{:?} document does not decrypt back", format));
+        }
+    }
+    Ok(())
+}
+
+/// Errors if, with `password` configured to be encrypted
+/// * the field of a TOML file is not encrypted as a string, or its comments are lost
+/// * the TOML file does not decrypt back to the same text
+/// * the same goes for a YAML file, whose comments are not kept
+pub async fn test_config_files(client: &Client) -> Result<(), String> {
+    client
+        .set_config(&["password".to_string()])
+        .await
+        .map_err(|e| format!("Failed to set configuration: {}", e))?;
+    let toml = "# service\nname = \"billing\" # shown\npassword = \"hunter2\" # rotate me\n\n[limits]\nrate = 5\n";
+    let yaml = "name: billing\npassword: hunter2\nlimits:\n  rate: 5\n";
+    for (format, document) in [(Format::Toml, toml), (Format::Yaml, yaml)] {
+        let encrypted = client
+            .encrypt_as(format, document.as_bytes())
+            .await
+            .map_err(|e| format!("Failed {:?} encryption: {}", format, e))?;
+        let text = String::from_utf8(encrypted.clone()).map_err(|e| e.to_string())?;
+        if text.contains("hunter2") || !text.contains("name") {
+            return Err(format!("{:?} field is not encrypted: {}", format, text));
+        }
+        if format == Format::Toml && !text.contains("# service") {
+            return Err(format!("TOML comments are lost: {}", text));
+        }
+        let decrypted = client
+            .decrypt_as(format, &encrypted)
+            .await
+            .map_err(|e| format!("Failed {:?} decryption: {}", format, e))?;
+        if decrypted != document.as_bytes() {
+            return Err(format!(
+                "{:?} file does not decrypt back: {}",
+                format,
+                String::from_utf8_lossy(&decrypted)
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * a redacted field is still there, at any depth
+/// * a masked, hashed or tokenized field does not have the expected form
+/// * a value does not always get the same token, or two values get the same token
+pub async fn test_field_actions(client: &Client, public_key: &RsaPublicKey) -> Result<(), String> {
+    client
+        .set_field_actions(
+            &["password".to_string()],
+            &json!({
+                "ssn": { "action": "redact" },
+                "card": { "action": "mask", "pattern": "****####" },
+                "email": { "action": "hash", "salt": "pepper" },
+                "customer": { "action": "tokenize" },
+            }),
+        )
+        .await
+        .map_err(|e| format!("Failed to set field actions: {}", e))?;
+    let data = json!({
+        "password": "hunter2",
+        "ssn": "078-05-1120",
+        "card": "4111111111111234",
+        "email": "jane@example.com",
+        "orders": [{ "customer": "jane", "ssn": 1 }, { "customer": "john" }, { "customer": "jane" }],
+    });
+    let safe = client
+        .encrypt(&data)
+        .await
+        .map_err(|e| format!("Failed to apply field actions: {}", e))?;
+    if !safe["password"]
+        .as_str()
+        .is_some_and(|password| field_crypto::looks_encrypted(password, public_key))
+    {
+        return Err(format!("Field is not encrypted: {}", safe));
+    }
+    if safe.get("ssn").is_some() || safe["orders"][0].get("ssn").is_some() {
+        return Err(format!("Field is not redacted: {}", safe));
+    }
+    if safe["card"] != "****1234" {
+        return Err(format!("Field is not masked: {}", safe["card"]));
+    }
+    if safe["email"] != field_crypto::actions::hash("jane@example.com", "pepper") {
+        return Err(format!("Field is not hashed: {}", safe["email"]));
+    }
+    let tokens = safe["orders"]
+        .as_array()
+        .ok_or("Expected orders")?
+        .iter()
+        .map(|order| order["customer"].as_str().unwrap_or_default())
+        .collect::<Vec<_>>();
+    if tokens[0] == "jane" || tokens[0] != tokens[2] || tokens[0] == tokens[1] {
+        return Err(format!("Fields are not tokenized: {:?}", tokens));
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * a tokenized field is not a token of the same format, or the same value gets another token
+/// * detokenizing does not give back the document, with its numbers
+/// * a string that is not a token is changed by detokenizing
+pub async fn test_token_vault(client: &Client) -> Result<(), String> {
+    client
+        .set_field_actions(&[], &json!({ "card": { "action": "tokenize" } }))
+        .await
+        .map_err(|e| format!("Failed to set field actions: {}", e))?;
+    let data = json!({
+        "card": "4111-1111-1111-1234",
+        "orders": [{ "card": 4111111111111234u64 }, { "card": "4111-1111-1111-1234" }],
+        "name": "Jane",
+    });
+    let tokenized = client
+        .tokenize(&data)
+        .await
+        .map_err(|e| format!("Failed to tokenize: {}", e))?;
+    let token = tokenized["card"].as_str().unwrap_or_default();
+    let same_format = token.len() == 19
+        && token
+            .chars()
+            .zip("4111-1111-1111-1234".chars())
+            .all(|(a, b)| a.is_ascii_digit() == b.is_ascii_digit() && (a == '-') == (b == '-'));
+    if !same_format || token == "4111-1111-1111-1234" || tokenized["name"] != "Jane" {
+        return Err(format!("Field is not tokenized: {}", tokenized));
+    }
+    if tokenized["orders"][1]["card"] != token || !tokenized["orders"][0]["card"].is_string() {
+        return Err(format!("Tokens are not consistent: {}", tokenized));
+    }
+    let detokenized = client
+        .detokenize(&tokenized)
+        .await
+        .map_err(|e| format!("Failed to detokenize: {}", e))?;
+    if detokenized != data {
+        return Err(format!("Expected {}, got {}", data, detokenized));
+    }
+    let unknown = json!({ "card": "0000-0000-0000-0000" });
+    let detokenized = client
+        .detokenize(&unknown)
+        .await
+        .map_err(|e| format!("Failed to detokenize: {}", e))?;
+    if detokenized != unknown {
+        return Err(format!("Unknown token changed: {}", detokenized));
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * a property the schema marks, directly, through `$ref`, beside a `$ref` or in its array items, is not
+///   encrypted
+/// * an unmarked property, or one of a class left out, is encrypted
+/// * a document that does not match the schema, or an unknown schema, is accepted
+/// * a schema whose name needs percent-encoding is not found under that name
+pub async fn test_schemas(client: &Client, public_key: &RsaPublicKey) -> Result<(), String> {
+    client
+        .upload_schema(
+            "order",
+            &json!({
+                "type": "object",
+                "required": ["id"],
+                "properties": {
+                    "id": { "type": "integer" },
+                    "card": { "type": "string", "x-encrypt": "pci" },
+                    "customer": { "$ref": "#/$defs/customer" },
+                    "notes": { "type": "array", "items": { "x-encrypt": true } },
+                    "billing": { "$ref": "#/$defs/address", "x-encrypt": "pci" },
+                },
+                "$defs": {
+                    "customer": {
+                        "type": "object",
+                        "properties": { "name": { "type": "string" }, "birthday": { "x-encrypt": "pii" } },
+                    },
+                    "address": { "type": "object", "properties": { "street": { "type": "string" } } },
+                },
+            }),
+        )
+        .await
+        .map_err(|e| format!("Failed to upload schema: {}", e))?;
+    let data = json!({
+        "id": 7,
+        "card": "4111111111111234",
+        "customer": { "name": "Jane", "birthday": "1990-01-31" },
+        "notes": ["call back", { "at": 9 }],
+        "billing": { "street": "1 Main St" },
+    });
+    let encrypted = client
+        .encrypt_with_schema("order", &data)
+        .await
+        .map_err(|e| format!("Failed to encrypt with schema: {}", e))?;
+    let is_encrypted = |value: &Value| {
+        value
+            .as_str()
+            .is_some_and(|value| field_crypto::looks_encrypted(value, public_key))
+    };
+    for path in ["/card", "/customer/birthday", "/notes", "/billing"] {
+        if !encrypted.pointer(path).is_some_and(is_encrypted) {
+            return Err(format!("{} is not encrypted: {}", path, encrypted));
+        }
+    }
+    if encrypted["id"] != 7 || encrypted["customer"]["name"] != "Jane" {
+        return Err(format!("Unmarked fields changed: {}", encrypted));
+    }
+    let decrypted = client
+        .decrypt(&encrypted)
+        .await
+        .map_err(|e| format!("Failed to decrypt: {}", e))?;
+    if decrypted != data {
+        return Err(format!("Expected {}, got {}", data, decrypted));
+    }
+    let (_, pci) = client
+        .post_text("/encrypt?schema=order&classes=pci", &data.to_string())
+        .await
+        .map_err(|e| format!("Failed to encrypt with classes: {}", e))?;
+    let pci: Value = serde_json::from_str(&pci).map_err(|e| e.to_string())?;
+    if !is_encrypted(&pci["card"])
+        || !is_encrypted(&pci["billing"])
+        || pci["customer"]["birthday"] != "1990-01-31"
+    {
+        return Err(format!("Classes are not applied: {}", pci));
+    }
+    for (schema, document) in [("order", json!({ "card": "4111" })), ("nope", data)] {
+        let (status, _) = client
+            .post_raw(
+                &format!("/encrypt?schema={}", schema),
+                &document.to_string(),
+            )
+            .await
+            .map_err(|e| format!("Failed to send request: {}", e))?;
+        if status != 400 {
+            return Err(format!(
+                "Expected 400 for {} with schema {}, got {}",
+                document, schema, status
+            ));
+        }
+    }
+    let name = "orders & returns/2024+?";
+    client
+        .upload_schema(
+            name,
+            &json!({ "properties": { "card": { "x-encrypt": true } } }),
+        )
+        .await
+        .map_err(|e| format!("Failed to upload schema {}: {}", name, e))?;
+    let encrypted = client
+        .encrypt_with_schema(name, &json!({ "card": "4111" }))
+        .await
+        .map_err(|e| format!("Failed to encrypt with schema {}: {}", name, e))?;
+    if !is_encrypted(&encrypted["card"]) {
+        return Err(format!("Schema {} is not applied: {}", name, encrypted));
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * an email, a card number, an IBAN, a national id or a phone number in clear is not detected
+/// * a configured field, a ciphertext, a number or a string that only looks alike is reported
+/// * with `PII_DETECTION=encrypt` set for a server encrypting what it detects, a detected field is not encrypted
+pub async fn test_pii(client: &Client, public_key: &RsaPublicKey) -> Result<(), String> {
+    client
+        .set_config(&["password".to_string()])
+        .await
+        .map_err(|e| format!("Failed to set config: {}", e))?;
+    let data = json!({
+        "password": "jane@example.com",
+        "contact": { "email": "jane@example.com", "phones": ["+44 20 7946 0958"] },
+        "note": "card 4111 1111 1111 1111 expires soon",
+        "payout": "GB82 WEST 1234 5698 7654 32",
+        "ssn": "078-05-1120",
+        "nino": "AB 12 34 56 C",
+        "order": 4111111111111111u64,
+        "reference": "4111 1111 1111 1112",
+        "date": "2024-01-31",
+    });
+    let detected = client
+        .detect_pii(&data)
+        .await
+        .map_err(|e| format!("Failed to detect personal data: {}", e))?;
+    let expected = json!({
+        "/contact/email": "email",
+        "/contact/phones": "phoneNumber",
+        "/note": "cardNumber",
+        "/payout": "iban",
+        "/ssn": "nationalId",
+        "/nino": "nationalId",
+    });
+    if detected != expected {
+        return Err(format!("Expected {}, got {}", expected, detected));
+    }
+    if std::env::var("PII_DETECTION").as_deref() == Ok("encrypt") {
+        let encrypted = client
+            .encrypt(&data)
+            .await
+            .map_err(|e| format!("Failed to encrypt: {}", e))?;
+        for path in expected
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(path, _)| path)
+        {
+            if !encrypted
+                .pointer(path)
+                .and_then(Value::as_str)
+                .is_some_and(|value| field_crypto::looks_encrypted(value, public_key))
+            {
+                return Err(format!("{} is not encrypted: {}", path, encrypted));
+            }
+        }
+        if encrypted["reference"] != data["reference"] {
+            return Err(format!("Undetected field changed: {}", encrypted));
+        }
+    }
+    Ok(())
+}
+
+/// Errors if
+/// * a dry run does not list the configured fields with their action, or a value too large for RSA without an
+///   error
+/// * a dry run of decryption does not list the ciphertexts
+/// * a dry run returns the document rather than the fields
+pub async fn test_dry_run(client: &Client) -> Result<(), String> {
+    client
+        .set_field_actions(
+            &["password".to_string(), "attachment".to_string()],
+            &json!({ "internal": { "action": "redact" } }),
+        )
+        .await
+        .map_err(|e| format!("Failed to set field actions: {}", e))?;
+    let data = json!({
+        "password": "hunter2",
+        "attachment": "x".repeat(1000),
+        "nested": [{ "internal": "n/a" }],
+    });
+    let planned = client
+        .plan_encryption(&data)
+        .await
+        .map_err(|e| format!("Failed to plan encryption: {}", e))?;
+    let field = |planned: &Value, path: &str| {
+        planned
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|field| field["path"] == path)
+            .cloned()
+            .unwrap_or_default()
+    };
+    let password = field(&planned, "/password");
+    let attachment = field(&planned, "/attachment");
+    let internal = field(&planned, "/nested/0/internal");
+    if password["action"] != "encrypt"
+        || password.get("error").is_some()
+        || internal["action"] != "redact"
+    {
+        return Err(format!("Configured fields are not planned: {}", planned));
+    }
+    if !attachment["error"]
+        .as_str()
+        .is_some_and(|error| error.starts_with("too large for RSA"))
+    {
+        return Err(format!(
+            "Value too large for RSA is not reported: {}",
+            planned
+        ));
+    }
+    let encrypted = client
+        .encrypt(&json!({ "password": "hunter2", "note": "plain" }))
+        .await
+        .map_err(|e| format!("Failed to encrypt: {}", e))?;
+    let planned = client
+        .plan_decryption(&encrypted)
+        .await
+        .map_err(|e| format!("Failed to plan decryption: {}", e))?;
+    let expected = json!([{ "path": "/password", "action": "decrypt", "reason": "ciphertext" }]);
+    if planned != expected {
+        return Err(format!("Expected {}, got {}", expected, planned));
+    }
+    Ok(())
+}
+
+/// Only meaningful when the server serves HTTPS and authenticates `identity`, a PKCS#12 client certificate, as
+/// a caller allowed to encrypt.
+/// Errors if
+/// * a request authenticated by the certificate alone fails, also the second one of a connection kept open
+///   (or of an HTTP/2 connection)
+pub async fn test_client_certificate(
+    builder: ClientBuilder,
+    identity: &[u8],
+    password: &str,
+) -> Result<(), String> {
+    let client = builder
+        .identity(identity, password)
+        .retries(0)
+        .build()
+        .map_err(|e| e.to_string())?;
+    // the client keeps its connection open between the requests
+    for attempt in ["first", "second"] {
+        client
+            .encrypt(&json!({ "nice": "to meet you" }))
+            .await
+            .map_err(|e| format!("Failed the {} request by certificate: {}", attempt, e))?;
+    }
+    Ok(())
+}
+
+/// Errors if, for `provider`
+/// * its key id is not the fingerprint of its public key
+/// * its signature of a digest does not verify with its public key, or verifies another digest
+/// * a block encrypted with its public key does not decrypt back, or a block of the wrong size decrypts
+/// * a data key wrapped with its public key does not unwrap
+pub fn test_key_provider(provider: &dyn KeyProvider) -> Result<(), String> {
+    if provider.key_id() != field_crypto::fingerprint(provider.public_key()) {
+        return Err("Key id is not the fingerprint of the public key".to_string());
+    }
+    let digest = field_crypto::signing_digest(&json!({ "sign": "me" }));
+    let signature = crypto::sign_digest(&digest, provider)?;
+    if !crypto::verify_digest(&digest, &signature, provider)? {
+        return Err("Signature does not verify".to_string());
+    }
+    let other = field_crypto::signing_digest(&json!({ "sign": "you" }));
+    if crypto::verify_digest(&other, &signature, provider)? {
+        return Err("Signature verifies another digest".to_string());
+    }
+    let block = field_crypto::encrypt_pub_slice(provider.public_key(), b"plaintext")?;
+    if *provider.decrypt(&block)? != b"plaintext"[..] {
+        return Err("Block does not decrypt back".to_string());
+    }
+    if provider.decrypt(&block[1..]).is_ok() {
+        return Err("Block of the wrong size decrypts".to_string());
+    }
+    let data_key = [7; 32];
+    let wrapped = crypto::wrap_key(provider, &data_key)?;
+    if *crypto::unwrap_key(provider, &wrapped)? != data_key {
+        return Err("Data key does not unwrap".to_string());
+    }
+    Ok(())
+}
+
+/// Errors if, for a new software key served by a signing agent
+/// * the agent cannot be reached
+/// * the agent's key is not the served one, or does not hold to `test_key_provider`
+#[cfg(unix)]
+pub fn test_agent_key(key: Arc<SoftwareKey>) -> Result<(), String> {
+    let socket = std::env::temp_dir().join(format!("testing-agent-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let served = key.clone();
+    let listening = socket.clone();
+    std::thread::spawn(move || agent::serve(served, &listening));
+    let mut connected = AgentKey::connect(socket.clone());
+    for _ in 0..50 {
+        if connected.is_ok() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        connected = AgentKey::connect(socket.clone());
     }
+    let agent_key = connected.map_err(|e| format!("Failed to reach the agent: {}", e))?;
+    let result = match agent_key.public_key() == key.public_key() {
+        true => test_key_provider(&agent_key),
+        false => Err("Agent key is not the served key".to_string()),
+    };
+    let _ = std::fs::remove_file(&socket);
+    result
 }
@@ -1,53 +1,180 @@
+use client::{Client, ClientBuilder};
 use colored::Colorize;
 use serde_json::{json, Value};
 
 mod checks;
 mod negative_checks;
 
-async fn run_tests(base_url: &str, client: &reqwest::Client, bodies: Vec<(String, Value)>) {
+async fn run_tests(client: &Client, audience: &str, bodies: Vec<(String, Value)>) {
     use checks::*;
+    let public_key = field_crypto::load_public_key("../solution/pub_key").unwrap();
     println!("{}", "==== Running Positive Tests ====".blue().bold());
     for (name, body) in bodies {
         println!("{}", format!("== Running test {} ==", name).blue());
-        let encrypted = test_encrypt(base_url, client, &body).await.unwrap();
+        let fields = body
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        test_config(client, &fields).await.unwrap();
+        println!("{}", "configuration passed".green());
+        let encrypted = test_encrypt(client, &body).await.unwrap();
         println!("{}", "encryption passed".green());
-        test_decrypt(base_url, client, &encrypted, &body)
-            .await
-            .unwrap();
+        test_decrypt(client, &encrypted, &body).await.unwrap();
         println!("{}", "decryption passed".green());
-        let signature = test_signature(base_url, client, &body).await.unwrap();
+        let signature = test_signature(client, &body).await.unwrap();
         println!("{}", "signature passed".green());
-        test_verification(base_url, client, &encrypted, &signature)
+        test_verification(client, &encrypted, &signature)
             .await
             .unwrap();
         println!("{}", "verification passed".green());
+        test_reencrypt(client, &encrypted).await.unwrap();
+        println!("{}", "re-encryption passed".green());
+        test_field_signatures(client, &body, &encrypted)
+            .await
+            .unwrap();
+        println!("{}", "field signatures passed".green());
+        test_envelope(client, &body, &encrypted, audience)
+            .await
+            .unwrap();
+        println!("{}", "envelope passed".green());
+        test_formats(client, &public_key, &body).await.unwrap();
+        println!("{}", "formats passed".green());
+        test_local_encrypt(client, &public_key, &body)
+            .await
+            .unwrap();
+        println!("{}", "local encryption passed".green());
+        test_local_verification(&public_key, &body, &signature).unwrap();
+        println!("{}", "local verification passed".green());
+    }
+    test_key_wrapping(client, &public_key).await.unwrap();
+    println!("{}", "key wrapping passed".green());
+    test_raw_signature(client).await.unwrap();
+    println!("{}", "raw signature passed".green());
+    test_blob(client).await.unwrap();
+    println!("{}", "blob encryption passed".green());
+    test_config_files(client).await.unwrap();
+    println!("{}", "configuration files passed".green());
+    test_field_actions(client, &public_key).await.unwrap();
+    println!("{}", "field actions passed".green());
+    test_schemas(client, &public_key).await.unwrap();
+    println!("{}", "schemas passed".green());
+    test_pii(client, &public_key).await.unwrap();
+    println!("{}", "personal data detection passed".green());
+    test_dry_run(client).await.unwrap();
+    println!("{}", "dry run passed".green());
+    // set when the server has a token vault
+    if std::env::var("TOKEN_VAULT").is_ok() {
+        test_token_vault(client).await.unwrap();
+        println!("{}", "token vault passed".green());
+    }
+}
+
+fn run_key_provider_tests() {
+    use checks::*;
+    println!("{}", "==== Running Key Provider Tests ====".blue().bold());
+    // small, as only the contract is checked and generating keys is slow in debug builds
+    let key = interview_problem::provider::SoftwareKey::generate(1024);
+    test_key_provider(&key).unwrap();
+    println!("{}", "software key passed".green());
+    #[cfg(unix)]
+    {
+        test_agent_key(std::sync::Arc::new(key)).unwrap();
+        println!("{}", "agent key passed".green());
     }
 }
 
-async fn run_negative_tests(base_url: &str, client: &reqwest::Client) {
+/// `builder` makes a builder for the server without credentials.
+async fn run_negative_tests(
+    builder: &dyn Fn() -> ClientBuilder,
+    client: &Client,
+    authenticated: bool,
+) {
     use negative_checks::*;
+    let public_key = field_crypto::load_public_key("../solution/pub_key").unwrap();
     println!("{}", "==== Running Negative Tests ====".blue().bold());
-    test_encrypt_empty(base_url, client).await.unwrap();
+    test_zero_limits().unwrap();
+    println!("{}", "zero limits passed".green());
+    if authenticated {
+        test_missing_credentials(builder()).await.unwrap();
+        println!("{}", "missing credentials passed".green());
+        // set with a key that may only encrypt
+        if let Ok(api_key) = std::env::var("ENCRYPT_ONLY_API_KEY") {
+            test_encoded_paths(builder(), &api_key).await.unwrap();
+            println!("{}", "encoded paths passed".green());
+        }
+        test_audience_is_caller(client).await.unwrap();
+        println!("{}", "audience of the caller passed".green());
+        // set with a key whose role may only verify, and only decrypt the `public` field
+        if let Ok(api_key) = std::env::var("VERIFY_PUBLIC_API_KEY") {
+            test_verify_restricted_fields(builder(), client, &api_key, &public_key)
+                .await
+                .unwrap();
+            println!("{}", "restricted verification passed".green());
+        }
+    }
+    test_key_wrapping_separate(client, &public_key)
+        .await
+        .unwrap();
+    println!("{}", "separate key wrapping passed".green());
+    test_plain_byte_strings(client).await.unwrap();
+    println!("{}", "plain byte strings passed".green());
+    test_looping_schemas(client).await.unwrap();
+    println!("{}", "looping schemas passed".green());
+    test_encrypt_empty(client).await.unwrap();
     println!("{}", "encrypting empty passed".green());
-    test_encrypt_array_first(base_url, client).await.unwrap();
+    test_encrypt_array_first(client).await.unwrap();
     println!("{}", "encrypting array first passed".green());
-    test_decrypt_empty(base_url, client).await.unwrap();
+    test_decrypt_empty(client).await.unwrap();
     println!("{}", "decrypting empty passed".green());
-    test_signature_invalid(base_url, client).await.unwrap();
+    test_signature_invalid(client).await.unwrap();
     println!("{}", "signing invalid passed".green());
-    test_verify_missing_signature(base_url, client)
+    test_verify_missing_signature(client).await.unwrap();
+    println!("{}", "verifying missing signature passed".green());
+    test_decrypt_failures_uniform(client, &public_key)
         .await
         .unwrap();
-    println!("{}", "verifying missing signature passed".green());
+    println!("{}", "uniform decryption failures passed".green());
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let base_url = "http://localhost:8080";
+    run_key_provider_tests();
+    // set to `https://localhost:8080` when the server serves HTTPS
+    let base_url =
+        std::env::var("BASE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+    let base_url = base_url.as_str();
+    // set with the PEM CA certificate of an HTTPS server
+    let ca_certificate = std::env::var("CA_CERTIFICATE")
+        .ok()
+        .map(std::fs::read)
+        .transpose()?;
+    let new_builder = || match &ca_certificate {
+        Some(ca_certificate) => Client::builder(base_url).root_certificate(ca_certificate),
+        None => Client::builder(base_url),
+    };
+    // retry while the server is still starting up
+    let mut builder = new_builder().retries(30);
+    // set when the server has authentication enabled, with a key holding every permission
+    let api_key = std::env::var("API_KEY").ok();
+    if let Some(api_key) = &api_key {
+        builder = builder.api_key(api_key);
+    }
+    let client = builder.build()?;
+    // set when the server has `requireEnvelope`, which the other checks' bare signatures would fail
+    if std::env::var("REQUIRE_ENVELOPE").is_ok() {
+        negative_checks::test_envelope_required(&client)
+            .await
+            .unwrap();
+        println!("{}", "required envelope passed".green());
+        return Ok(());
+    }
+    // set with the id of the caller of API_KEY, the only audience its envelopes verify for
+    let audience = std::env::var("CALLER_ID").unwrap_or_else(|_| "testing".to_string());
     run_tests(
-        base_url,
         &client,
+        &audience,
         vec![
             (
                 "test1".to_string(),
@@ -95,6 +222,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         ],
     )
     .await;
-    run_negative_tests(base_url, &client).await;
+    // set with a PKCS#12 client certificate the server authenticates, and its password
+    if let Ok(identity) = std::env::var("CLIENT_IDENTITY") {
+        let password = std::env::var("CLIENT_IDENTITY_PASSWORD").unwrap_or_default();
+        checks::test_client_certificate(new_builder(), &std::fs::read(identity)?, &password)
+            .await
+            .unwrap();
+        println!("{}", "client certificate passed".green());
+    }
+    run_negative_tests(&new_builder, &client, api_key.is_some()).await;
     Ok(())
 }
@@ -1,134 +1,384 @@
+use client::{Client, ClientBuilder, Error};
+use field_crypto::format::Format;
+use field_crypto::rsa::{PublicKeyParts, RsaPublicKey};
+use interview_problem::limits::LimitSettings;
 use serde_json::json;
 
+/// Errors unless `result` is a 400 Bad Request whose body is exactly `expected`.
+fn expect_bad_request<T>(
+    result: Result<T, Error>,
+    expected: &str,
+    endpoint: &str,
+) -> Result<(), String> {
+    match result {
+        Err(Error::Status { status: 400, body }) if body == expected => Ok(()),
+        Err(Error::Status { status: 400, .. }) => {
+            Err(format!("Expected {} from {}", expected, endpoint))
+        }
+        _ => Err(format!(
+            "Expected 400 Bad Request status code from {}",
+            endpoint
+        )),
+    }
+}
+
 /// Errors if
 /// * no body is received
 /// * the response is not a 400 Bad Request with a specific parsing error message
-pub async fn test_encrypt_empty(base_url: &str, client: &reqwest::Client) -> Result<(), String> {
-    let url = format!("{}/encrypt", base_url);
-    let response = client
-        .post(&url)
-        .body("")
-        .send()
-        .await
-        .map_err(|_| "Failed to send request")?;
-    if response.status() != 400 {
-        return Err("Expected 400 Bad Request status code in encryption".to_string());
-    }
-    let body = response
-        .text()
-        .await
-        .map_err(|_| "Failed to receive response with body from encryption")?;
-    if body != "EOF while parsing a value at line 1 column 0" {
-        return Err("Expected EOF while parsing a nonexistent value in encryption".to_string());
-    }
-    Ok(())
+pub async fn test_encrypt_empty(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client.post_text("/encrypt", "").await,
+        "EOF while parsing a value at line 1 column 0",
+        "encryption",
+    )
 }
 
 /// Errors if
 /// * no body is received
 /// * the response is not a 400 Bad Request with a specific parsing error message
-pub async fn test_encrypt_array_first(
-    base_url: &str,
-    client: &reqwest::Client,
+pub async fn test_encrypt_array_first(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client.encrypt(&json!(["hin", "hoi"])).await,
+        "data must be a json map on the first level",
+        "encryption",
+    )
+}
+
+/// Errors if
+/// * no body is received
+/// * the decryption response is not a 400 Bad Request with a specific parsing error message
+pub async fn test_decrypt_empty(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client.post_text("/decrypt", "").await,
+        "EOF while parsing a value at line 1 column 0",
+        "decryption",
+    )
+}
+
+/// Errors if
+/// * no body is received
+/// * the decryption response is not a 400 Bad Request with a specific parsing error message
+pub async fn test_signature_invalid(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client
+            .post_text("/sign", "abcdefg, this is not valid json, hijklmnop.")
+            .await,
+        "expected value at line 1 column 1",
+        "signature",
+    )
+}
+
+/// Errors if
+/// * no body is received
+/// * the decryption response is not a 400 Bad Request with a specific parsing error message
+pub async fn test_verify_missing_signature(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client
+            .post_text("/verify", &json!({"data": "abcdefg"}).to_string())
+            .await,
+        "missing signature",
+        "verification",
+    )
+}
+
+/// Errors if
+/// * a string failing to decrypt is not returned exactly as it was sent, whichever step fails
+///   (base64, block length, padding, utf8, json, a field encrypted at another path)
+pub async fn test_decrypt_failures_uniform(
+    client: &Client,
+    public_key: &RsaPublicKey,
 ) -> Result<(), String> {
-    let url = format!("{}/encrypt", base_url);
-    let response = client
-        .post(&url)
-        .body(json!(["hin", "hoi"]).to_string())
-        .send()
-        .await
-        .map_err(|_| "Failed to send request")?;
-    if response.status() != 400 {
-        return Err("Expected 400 Bad Request status code in encryption".to_string());
+    let encrypt = |plaintext: &[u8]| {
+        field_crypto::encrypt_pub_slice(public_key, plaintext)
+            .map(|c| field_crypto::encode_ciphertext(&c))
+    };
+    let failures = [
+        ("invalid base64", "not base64 at all!".to_string()),
+        ("short block", field_crypto::encode_ciphertext(&[1; 16])),
+        (
+            "invalid padding",
+            field_crypto::encode_ciphertext(&vec![0x42; public_key.size()]),
+        ),
+        ("invalid utf8", encrypt(&[0xff, 0xfe, 0xfd])?),
+        ("invalid json", encrypt(b"not json")?),
+        (
+            "moved",
+            field_crypto::encrypt_field(public_key, "/elsewhere", &json!("secret"))?,
+        ),
+    ];
+    for (name, ciphertext) in failures {
+        let document = json!({ "field": ciphertext });
+        match client.decrypt(&document).await {
+            Ok(response) if response == document => {}
+            _ => {
+                return Err(format!(
+                    "Expected the {} field to be returned unchanged from decryption",
+                    name
+                ))
+            }
+        }
     }
-    let body = response
-        .text()
+    Ok(())
+}
+
+/// Errors if
+/// * a data key larger than a wrapped key holds is not refused with a 400 Bad Request
+/// * an encrypted field unwraps as a data key
+/// * a wrapped data key decrypts as a field
+pub async fn test_key_wrapping_separate(
+    client: &Client,
+    public_key: &RsaPublicKey,
+) -> Result<(), String> {
+    expect_bad_request(
+        client.data_key(100_000_000_000_000).await,
+        &format!(
+            "data keys can be at most {} bytes",
+            field_crypto::max_data_key_size(public_key)
+        ),
+        "data key generation",
+    )?;
+    let field = field_crypto::encrypt_pub_string(public_key, "\"secret\"")?;
+    expect_bad_request(
+        client.unwrap("current", &field).await,
+        "decryption failed",
+        "unwrapping",
+    )?;
+    let data_key = client
+        .data_key(32)
         .await
-        .map_err(|_| "Failed to receive response with body from encryption")?;
-    if body != "data must be a json map on the first level" {
-        return Err(
-            "Expected data must be a json map on the first level in encryption".to_string(),
-        );
+        .map_err(|e| format!("Failed to generate a data key: {}", e))?;
+    let document = json!({ "field": data_key.wrapped });
+    match client.decrypt(&document).await {
+        Ok(response) if response == document => Ok(()),
+        _ => Err("Expected a wrapped key to be returned unchanged from decryption".to_string()),
+    }
+}
+
+/// Errors if
+/// * a CBOR or MessagePack byte string that is not a ciphertext is not refused with a 400 Bad Request, as it
+///   would come back as text
+pub async fn test_plain_byte_strings(client: &Client) -> Result<(), String> {
+    // {"raw": h'0102'}
+    let cbor = [0xa1, 0x63, b'r', b'a', b'w', 0x42, 0x01, 0x02];
+    let message_pack = [0x81, 0xa3, b'r', b'a', b'w', 0xc4, 0x02, 0x01, 0x02];
+    for (format, document) in [
+        (Format::Cbor, &cbor[..]),
+        (Format::MessagePack, &message_pack),
+    ] {
+        expect_bad_request(
+            client.encrypt_as(format, document).await,
+            "byte strings must be ciphertexts of the server's key",
+            "encryption",
+        )?;
     }
     Ok(())
 }
 
 /// Errors if
-/// * no body is received
-/// * the decryption response is not a 400 Bad Request with a specific parsing error message
-pub async fn test_decrypt_empty(base_url: &str, client: &reqwest::Client) -> Result<(), String> {
-    let url = format!("{}/decrypt", base_url);
-    let response = client
-        .post(&url)
-        .body("")
-        .send()
-        .await
-        .map_err(|_| "Failed to send request")?;
-    if response.status() != 400 {
-        return Err("Expected 400 Bad Request status code in decryption".to_string());
+/// * a schema applying itself to the same value again, which would validate forever, is not refused with a
+///   400 Bad Request, directly or through a property
+pub async fn test_looping_schemas(client: &Client) -> Result<(), String> {
+    expect_bad_request(
+        client
+            .upload_schema("loop", &json!({ "allOf": [{ "$ref": "#" }] }))
+            .await,
+        "schema applies itself to the same value again",
+        "schema upload",
+    )?;
+    expect_bad_request(
+        client
+            .upload_schema(
+                "loop",
+                &json!({
+                    "properties": { "a": { "$ref": "#/$defs/a" } },
+                    "$defs": { "a": { "anyOf": [{ "$ref": "#/$defs/a" }] } },
+                }),
+            )
+            .await,
+        "schema applies itself to the same value again at /properties/a",
+        "schema upload",
+    )
+}
+
+/// Only meaningful when the server has authentication enabled.
+/// Errors if
+/// * a request without credentials is not rejected with 401 Unauthorized
+pub async fn test_missing_credentials(builder: ClientBuilder) -> Result<(), String> {
+    let client = builder.build().map_err(|e| e.to_string())?;
+    match client.encrypt(&json!({"nice": "to meet you"})).await {
+        Err(Error::Status { status: 401, .. }) => Ok(()),
+        _ => Err("Expected 401 Unauthorized status code without credentials".to_string()),
     }
-    let body = response
-        .text()
+}
+
+/// Only meaningful when the server has authentication enabled.
+/// Errors if
+/// * an envelope is verified for an audience other than the caller, whether named or not
+pub async fn test_audience_is_caller(client: &Client) -> Result<(), String> {
+    let data = json!({ "to": "someone else" });
+    let signed = client
+        .sign_envelope(&data, Some(60), Some("someone else"))
         .await
-        .map_err(|_| "Failed to receive response with body from decryption")?;
-    if body != "EOF while parsing a value at line 1 column 0" {
-        return Err("Expected EOF while parsing a nonexistent value in decryption".to_string());
+        .map_err(|e| format!("Failed envelope signing: {}", e))?;
+    expect_bad_request(
+        client
+            .verify_envelope(&data, &signed, Some("someone else"))
+            .await,
+        "audience must be the caller's id",
+        "verification",
+    )?;
+    expect_bad_request(
+        client.verify_envelope(&data, &signed, None).await,
+        "envelope is for another audience",
+        "verification",
+    )
+}
+
+/// Errors if
+/// * a `limits` block with a rate or `maxConcurrentRequests` of zero loads
+/// * one with limits of one does not
+pub fn test_zero_limits() -> Result<(), String> {
+    for limit in [
+        "requestsPerMinute",
+        "globalRequestsPerMinute",
+        "maxConcurrentRequests",
+    ] {
+        if serde_json::from_value::<LimitSettings>(json!({ limit: 0 })).is_ok() {
+            return Err(format!("{} of 0 was accepted", limit));
+        }
+        serde_json::from_value::<LimitSettings>(json!({ limit: 1 }))
+            .map_err(|e| format!("{} of 1 was refused: {}", limit, e))?;
     }
     Ok(())
 }
 
+/// Only meaningful when the server has `requireEnvelope` set.
 /// Errors if
-/// * no body is received
-/// * the decryption response is not a 400 Bad Request with a specific parsing error message
-pub async fn test_signature_invalid(
-    base_url: &str,
-    client: &reqwest::Client,
-) -> Result<(), String> {
-    let url = format!("{}/sign", base_url);
-    let response = client
-        .post(&url)
-        .body("abcdefg, this is not valid json, hijklmnop.")
-        .send()
+/// * a bare signature or field signatures are not refused with a 400 Bad Request naming why
+/// * a signature in an envelope does not verify
+pub async fn test_envelope_required(client: &Client) -> Result<(), String> {
+    let data = json!({ "amount": 10 });
+    let signature = client
+        .sign(&data)
         .await
-        .map_err(|_| "Failed to send request")?;
-    if response.status() != 400 {
-        return Err("Expected 400 Bad Request status code from signature".to_string());
-    }
-    let body = response
-        .text()
+        .map_err(|e| format!("Failed signing: {}", e))?;
+    expect_bad_request(
+        client.verify(&data, &signature).await,
+        "signature must be in an envelope",
+        "verification",
+    )?;
+    let signatures = client
+        .sign_fields(&data)
+        .await
+        .map_err(|e| format!("Failed field signing: {}", e))?;
+    expect_bad_request(
+        client.verify_fields(&data, &signatures).await,
+        "signature must be in an envelope",
+        "field verification",
+    )?;
+    let signed = client
+        .sign_envelope(&data, Some(60), None)
         .await
-        .map_err(|_| "Failed to receive response with body from signature")?;
-    if body != "expected value at line 1 column 1" {
-        return Err("Expected expected value at line 1 column 1".to_string());
+        .map_err(|e| format!("Failed envelope signing: {}", e))?;
+    match client.verify_envelope(&data, &signed, None).await {
+        Ok(true) => Ok(()),
+        _ => Err("Envelope did not verify".to_string()),
     }
+}
 
-    Ok(())
+/// Only meaningful when the server has authentication enabled, with `api_key` only allowed to encrypt.
+/// Errors if
+/// * a route the key may not use is reached, also through a percent-encoded path
+/// * the key may not encrypt through a percent-encoded path
+pub async fn test_encoded_paths(builder: ClientBuilder, api_key: &str) -> Result<(), String> {
+    let client = builder
+        .api_key(api_key)
+        .build()
+        .map_err(|e| e.to_string())?;
+    for path in [
+        "/decrypt",
+        "/%64ecrypt",
+        "/%63onfig",
+        "/%73ign",
+        "/keys/current/%75nwrap",
+    ] {
+        match client.post_raw(path, "{}").await {
+            Ok((403, _)) => {}
+            result => {
+                return Err(format!(
+                    "Expected 403 Forbidden for {}, got {:?}",
+                    path, result
+                ))
+            }
+        }
+    }
+    match client.post_raw("/%65ncrypt", "{}").await {
+        Ok((200, _)) => Ok(()),
+        result => Err(format!("Expected 200 OK for /%65ncrypt, got {:?}", result)),
+    }
 }
 
+/// Only meaningful when the server has authentication enabled, with `api_key` only allowed to verify, and to
+/// see decrypted the `public` field alone.
 /// Errors if
-/// * no body is received
-/// * the decryption response is not a 400 Bad Request with a specific parsing error message
-pub async fn test_verify_missing_signature(
-    base_url: &str,
-    client: &reqwest::Client,
+/// * a signature is checked over an encrypted field the key may not decrypt, whole or field by field
+/// * a signature over an encrypted field the key may decrypt does not verify
+/// * an encrypted field the key may not decrypt is decrypted once moved to one it may
+pub async fn test_verify_restricted_fields(
+    builder: ClientBuilder,
+    client: &Client,
+    api_key: &str,
+    public_key: &RsaPublicKey,
 ) -> Result<(), String> {
-    let url = format!("{}/verify", base_url);
-    let response = client
-        .post(&url)
-        .body(json!({"data": "abcdefg"}).to_string())
-        .send()
+    let restricted = builder
+        .api_key(api_key)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let data = json!({ "public": "hello", "secret": "1234" });
+    let signature = client
+        .sign(&data)
+        .await
+        .map_err(|e| format!("Failed to sign: {}", e))?;
+    let signature_of_moved = client
+        .sign(&json!({ "public": data["secret"] }))
+        .await
+        .map_err(|e| format!("Failed to sign: {}", e))?;
+    let signatures = client
+        .sign_fields(&data)
+        .await
+        .map_err(|e| format!("Failed to sign fields: {}", e))?;
+    let public = field_crypto::detect_and_encrypt(&data, public_key, &["public".to_string()])?;
+    if !restricted
+        .verify(&public, &signature)
         .await
-        .map_err(|_| "Failed to send request")?;
-    if response.status() != 400 {
-        return Err("Expected 400 Bad Request status code from verificaton".to_string());
+        .map_err(|e| format!("Failed verification: {}", e))?
+    {
+        return Err("Expected a signature over a field the key may decrypt to verify".to_string());
     }
-    let body = response
-        .text()
+    let secret = field_crypto::detect_and_encrypt(
+        &data,
+        public_key,
+        &["public".to_string(), "secret".to_string()],
+    )?;
+    let moved = json!({ "public": secret["secret"] });
+    if restricted
+        .verify(&moved, &signature_of_moved)
         .await
-        .map_err(|_| "Failed to receive response with body from verification")?;
-    if body != "missing signature" {
-        return Err("Expected missing signature".to_string());
+        .map_err(|e| format!("Failed verification: {}", e))?
+    {
+        return Err(
+            "Expected a field moved to one the key may decrypt to stay encrypted".to_string(),
+        );
     }
-
-    Ok(())
+    let refused = "not allowed to decrypt /secret to verify";
+    expect_bad_request(
+        restricted.verify(&secret, &signature).await,
+        refused,
+        "verification",
+    )?;
+    expect_bad_request(
+        restricted.verify_fields(&secret, &signatures).await,
+        refused,
+        "field verification",
+    )
 }